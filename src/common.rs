@@ -1,63 +1,625 @@
-use super::{one_of, Any, PResult, Parser, Stream, EOF};
+use super::{
+    none_of, one_of, satisfy, take_while, take_while1, Any, Either, Error, Expected, PResult,
+    Parser, Span, Stream, Symbol, EOF,
+};
+use std::ops::{Neg, RangeInclusive};
 
 pub fn ws<'i>(stream: Stream<'i>) -> PResult<'i, ()> {
     let spaces = one_of(" \n\r\t").many().rule("ws");
     spaces.map(|_| ()).parse(stream)
 }
 
+/// A comment from `prefix` up to, but not including, the next `\n` or EOF.
+/// Returns the text after the prefix.
+pub fn line_comment<'i>(prefix: &'static str) -> impl Parser<'i, Result = &'i str> {
+    move |stream: Stream<'i>| {
+        let body = take_while(|c| c != '\n');
+        prefix.ignore_prev(body).parse(stream)
+    }
+}
+
+/// A comment from `open` to `close`, returning the text between them. With
+/// `nested`, inner `open`s must be closed too, so `/* a /* b */ c */` is one
+/// comment.
+pub fn block_comment<'i>(
+    open: &'static str,
+    close: &'static str,
+    nested: bool,
+) -> impl Parser<'i, Result = &'i str> {
+    move |stream: Stream<'i>| {
+        let (s, _) = Parser::parse(&open, stream.clone())?;
+        let rest = s.remaining();
+        let (mut depth, mut i) = (1, 0);
+        while let Some(ch) = rest[i..].chars().next() {
+            if rest[i..].starts_with(close) {
+                depth -= 1;
+                if depth == 0 {
+                    return s.advance_bytes(i + close.len()).ok(&rest[..i]);
+                }
+                i += close.len();
+            } else if nested && rest[i..].starts_with(open) {
+                depth += 1;
+                i += open.len();
+            } else {
+                i += ch.len_utf8();
+            }
+        }
+        let span = stream.span_to(&s);
+        stream.err_at(span, "unterminated block comment".to_string().into())
+    }
+}
+
+/// Like `ws`, but also skips `//` line comments and `/* */` block comments,
+/// nested if `nested` is set. A `/*` that is never closed is an error.
+pub fn ws_and_comments<'i>(nested: bool) -> impl Parser<'i, Result = ()> {
+    move |stream: Stream<'i>| {
+        let space = one_of(" \n\r\t").map(|_| ());
+        let line = line_comment("//").map(|_| ());
+        let block = block_comment("/*", "*/", nested).map(|_| ());
+        let p = space.or(line).or(block.clone()).many().rule("ws");
+        let (s, _) = p.parse(stream)?;
+        // `many` stops at a block comment that doesn't close, which would
+        // leave the `/*` to the next parser; report the comment instead.
+        if s.remaining().starts_with("/*") {
+            return block.parse(s);
+        }
+        s.ok(())
+    }
+}
+
+pub fn strip_bom<'i>(stream: Stream<'i>) -> PResult<'i, ()> {
+    if stream.offset() != 0 {
+        return stream.ok(());
+    }
+    '\u{feff}'.opt().map(|_| ()).parse(stream)
+}
+
+pub fn line_start<'i>(stream: Stream<'i>) -> PResult<'i, ()> {
+    if stream.is_at_line_start() {
+        stream.ok(())
+    } else {
+        stream.err("expected start of line".to_string().into())
+    }
+}
+
 pub fn ident<'i>(stream: Stream<'i>) -> PResult<'i, String> {
-    let letter = ('a'..='z').or('A'..='Z').or('_');
-    let letter_or_digit = letter.or('0'..='9');
-    let ident = letter
-        .prepend(letter_or_digit.many())
-        .as_string()
-        .rule("ident");
-    ident.parse(stream)
+    ident_with("").parse(stream)
 }
 
-fn escape<'i>(stream: Stream<'i>) -> PResult<'i, char> {
-    fn escape_code<'i>(stream: Stream<'i>) -> PResult<'i, char> {
-        let digit = ('0'..='9').or('a'..='f').or('A'..='F');
-        let p = "\\x"
-            .ignore_prev(digit.in_range(2..=2))
-            .or("\\u{".ignore_prev(digit.in_range(1..=6)).ignore_this('}'));
-        p.parse(stream.clone()).and_then(|(s, digits)| {
-            let code = u32::from_str_radix(&String::from_iter(digits), 16).unwrap();
-            if let Some(c) = char::from_u32(code) {
-                s.ok(c)
+pub fn ident_with<'i>(extra: &'static str) -> impl Parser<'i, Result = String> {
+    move |stream: Stream<'i>| {
+        let letter = satisfy(|c| c.is_ascii_alphabetic() || c == '_', None);
+        let letter_or_digit = satisfy(
+            move |c| c.is_ascii_alphanumeric() || c == '_' || extra.contains(c),
+            None,
+        );
+        let ident = letter
+            .prepend(letter_or_digit.many())
+            .as_string()
+            .rule("ident");
+        ident.parse(stream)
+    }
+}
+
+pub fn interned_ident<'i>(stream: Stream<'i>) -> PResult<'i, Symbol> {
+    let (end, _) = ident.parse(stream.clone())?;
+    let symbol = end.intern(stream.slice_to(&end));
+    end.ok(symbol)
+}
+
+pub fn ident_except<'i>(keywords: &'static [&'static str]) -> impl Parser<'i, Result = String> {
+    move |stream: Stream<'i>| {
+        let (s, ident) = ident.parse(stream.clone())?;
+        if keywords.contains(&ident.as_str()) {
+            stream.err(format!("unexpected keyword {:?}", ident).into())
+        } else {
+            s.ok(ident)
+        }
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let next = (prev + (ca != cb) as usize)
+                .min(row[j] + 1)
+                .min(row[j + 1] + 1);
+            prev = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    row[b.len()]
+}
+
+pub fn keyword_set<'i>(words: &'static [&'static str]) -> impl Parser<'i, Result = &'static str> {
+    move |stream: Stream<'i>| {
+        let Ok((end, _)) = ident.parse(stream.clone()) else {
+            return stream.err(Expected::Rule("keyword").into());
+        };
+        let word = stream.slice_to(&end);
+        if let Some(keyword) = words.iter().find(|&&keyword| keyword == word) {
+            return end.ok(*keyword);
+        }
+        let closest = words
+            .iter()
+            .map(|keyword| (edit_distance(word, keyword), keyword))
+            .filter(|(distance, _)| *distance <= 2)
+            .min_by_key(|(distance, _)| *distance);
+        match closest {
+            Some((_, keyword)) => {
+                let span = stream.span_to(&end);
+                let message = format!("unknown keyword `{word}`");
+                let err = Error::new(stream.clone(), message.into());
+                let err = err.with_note(span, format!("did you mean `{keyword}`?"));
+                Err(stream.catch(err))
+            }
+            None => stream.err(Expected::Rule("keyword").into()),
+        }
+    }
+}
+
+fn slice_of_some<'i>(
+    pred: fn(char) -> bool,
+    rule: &'static str,
+    stream: Stream<'i>,
+) -> PResult<'i, &'i str> {
+    take_while1(pred).rule(rule).parse(stream)
+}
+
+fn hex_digit<'i>(stream: Stream<'i>) -> PResult<'i, char> {
+    ('0'..='9').or('a'..='f').or('A'..='F').parse(stream)
+}
+
+pub fn digits1<'i>(stream: Stream<'i>) -> PResult<'i, &'i str> {
+    slice_of_some(|c| c.is_ascii_digit(), "digits", stream)
+}
+
+pub fn hex_digits1<'i>(stream: Stream<'i>) -> PResult<'i, &'i str> {
+    slice_of_some(|c| c.is_ascii_hexdigit(), "hex digits", stream)
+}
+
+pub fn alpha1<'i>(stream: Stream<'i>) -> PResult<'i, &'i str> {
+    slice_of_some(|c| c.is_ascii_alphabetic(), "letters", stream)
+}
+
+fn escape_code<'i>(stream: Stream<'i>) -> PResult<'i, char> {
+    let hex = 'x'.ignore_prev(hex_digit.count(2));
+    let unicode = "u{".ignore_prev(hex_digit.in_range(1..=6));
+    let p = hex.or(unicode.ignore_this('}'));
+    let p = p.and_then(|digits, s| {
+        let code = u32::from_str_radix(&String::from_iter(digits), 16).unwrap();
+        match char::from_u32(code) {
+            Some(c) => s.ok(c),
+            None => s.err(format!("invalid unicode code {:x}", code).into()),
+        }
+    });
+    p.parse(stream)
+}
+
+pub fn escape_with<'i>(
+    intro: char,
+    mappings: &'static [(char, char)],
+    codes: bool,
+) -> impl Parser<'i, Result = char> {
+    move |stream: Stream<'i>| {
+        let mapped = move |stream: Stream<'i>| {
+            let (end, ch) = stream.next();
+            match mappings.iter().find(|&&(escaped, _)| escaped == ch) {
+                Some(&(_, literal)) => end.ok(literal),
+                None => stream.err(Expected::Rule("escape").into()),
+            }
+        };
+        let code = move |stream: Stream<'i>| {
+            if codes {
+                escape_code(stream)
             } else {
-                stream.err(format!("invalid unicode code {:x}", code).into())
+                stream.err(Expected::Rule("escape").into())
             }
-        })
+        };
+        let p = intro.ignore_prev(mapped.or(code));
+        p.rule("escape").parse(stream)
     }
+}
 
-    let p = "\\n"
-        .map(|_| '\n')
-        .or("\\0".map(|_| '\0'))
-        .or("\\r".map(|_| '\r'))
-        .or("\\t".map(|_| '\t'))
-        .or("\\\\".map(|_| '\\'))
-        .or(escape_code);
-    p.rule("escape").parse(stream)
+fn escape<'i>(stream: Stream<'i>) -> PResult<'i, char> {
+    let mappings = &[
+        ('n', '\n'),
+        ('0', '\0'),
+        ('r', '\r'),
+        ('t', '\t'),
+        ('\\', '\\'),
+    ];
+    escape_with('\\', mappings, true).parse(stream)
 }
 
 pub fn string<'i>(stream: Stream<'i>) -> PResult<'i, String> {
-    let ch = escape
-        .or("\\\"".map(|_| '"'))
-        .or(Any.and_not(EOF).and_not('"'));
+    let ch = escape.or("\\\"".value('"')).or(none_of("\""));
     let str = '"'.ignore_prev(ch.many().ignore_this('"'));
     let str = str.as_string().rule("string");
     str.parse(stream)
 }
 
+/// Like `string`, but rejects raw control characters (U+0000 to U+001F), as
+/// JSON does: they must be written as escapes.
+pub fn strict_string<'i>(stream: Stream<'i>) -> PResult<'i, String> {
+    let (s, str) = string.parse(stream.clone())?;
+    let source = stream.slice_to(&s);
+    match source.char_indices().find(|&(_, ch)| ch < ' ') {
+        None => s.ok(str),
+        Some((i, ch)) => {
+            let start = stream.offset() + i;
+            let span = Span {
+                start,
+                end: start + 1,
+            };
+            let message = format!("unescaped control character U+{:04X} in string", ch as u32);
+            stream.err_at(span, message.into())
+        }
+    }
+}
+
+pub fn byte_escape_string<'i>(stream: Stream<'i>) -> PResult<'i, String> {
+    let byte = "\\x".ignore_prev(hex_digit.count(2));
+    let byte = byte.map(|digits| u8::from_str_radix(&String::from_iter(digits), 16).unwrap());
+    let ch = escape.or("\\\"".value('"')).or(none_of("\""));
+    let str = '"'.ignore_prev(byte.or_either(ch).many().ignore_this('"'));
+    let (s, pieces) = str.rule("string").parse(stream.clone())?;
+    let mut bytes = vec![];
+    for piece in pieces {
+        match piece {
+            Either::Left(byte) => bytes.push(byte),
+            Either::Right(ch) => bytes.extend_from_slice(ch.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(str) => s.ok(str),
+        Err(_) => stream.err_at(
+            stream.span_to(&s),
+            "invalid UTF-8 in string".to_string().into(),
+        ),
+    }
+}
+
 pub fn character<'i>(stream: Stream<'i>) -> PResult<'i, char> {
-    let ch = escape
-        .or("\\'".map(|_| '\''))
-        .or(Any.and_not(EOF).and_not('\''));
+    let ch = escape.or("\\'".value('\'')).or(none_of("'"));
     let char = '\''.ignore_prev(ch.ignore_this('\'')).rule("character");
     char.parse(stream)
 }
 
+/// A character class such as `[a-z0-9_]` or `[^"\\]`, produced by
+/// `char_class`. As a parser it consumes one char that `matches` accepts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CharClass {
+    pub negated: bool,
+    pub ranges: Vec<RangeInclusive<char>>,
+}
+
+impl CharClass {
+    pub fn matches(&self, ch: char) -> bool {
+        self.ranges.iter().any(|range| range.contains(&ch)) != self.negated
+    }
+}
+
+impl<'i> Parser<'i> for CharClass {
+    type Result = char;
+
+    fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
+        let (end, ch) = stream.next();
+        if ch != EOF && self.matches(ch) {
+            end.ok(ch)
+        } else {
+            stream.err(Expected::Rule("character class").into())
+        }
+    }
+}
+
+/// Parses a bracketed character class: an optional leading `^` negates it,
+/// `a-z` is a range and any other char is a literal. A `-` first or last in
+/// the class is a literal too, and `\` escapes `]`, `-` or `\` itself.
+pub fn char_class<'i>(stream: Stream<'i>) -> PResult<'i, CharClass> {
+    let literal = escape
+        .or('\\'.ignore_prev(Any.and_not(EOF)))
+        .or(none_of("]"));
+    let item = literal
+        .seq('-'.ignore_prev(literal.clone()).opt())
+        .spanned();
+    let class = '['.ignore_prev('^'.opt().seq(item.some())).ignore_this(']');
+    let (s, (negated, items)) = class.rule("character class").parse(stream.clone())?;
+    let mut ranges = Vec::with_capacity(items.len());
+    for (span, (lo, hi)) in items {
+        let hi = hi.unwrap_or(lo);
+        if lo > hi {
+            let message = format!("invalid range {lo:?}-{hi:?}");
+            return stream.err_at(span, message.into());
+        }
+        ranges.push(lo..=hi);
+    }
+    let class = CharClass {
+        negated: negated.is_some(),
+        ranges,
+    };
+    s.ok(class)
+}
+
+pub fn word<'i>(stream: Stream<'i>) -> PResult<'i, String> {
+    let raw_ch = Any.and_not(EOF).and_not('\'');
+    let raw = '\''.ignore_prev(raw_ch.many().ignore_this('\''));
+    let bare = Any.and_not(EOF).and_not(one_of(" \t\r\n\"'")).some();
+    let word = string.or(raw.or(bare).as_string()).rule("word");
+    word.parse(stream)
+}
+
+pub fn delimited_field<'i>(delim: char, quote: char) -> impl Parser<'i, Result = String> {
+    move |stream: Stream<'i>| {
+        let quoted_ch = quote.ignore_prev(quote).or(Any.and_not(quote));
+        let quoted = quote.ignore_prev(quoted_ch.many().ignore_this(quote));
+        let unquoted = Any.and_not(delim).and_not(quote).and_not(one_of("\r\n"));
+        quoted.or(unquoted.many()).as_string().parse(stream)
+    }
+}
+
+pub fn csv_field<'i>(stream: Stream<'i>) -> PResult<'i, String> {
+    delimited_field(',', '"').parse(stream)
+}
+
+pub fn csv_record<'i>(stream: Stream<'i>) -> PResult<'i, Vec<String>> {
+    csv_field.list(',').parse(stream)
+}
+
+pub fn csv<'i>(stream: Stream<'i>) -> PResult<'i, Vec<Vec<String>>> {
+    let line_end = "\r\n".or("\n");
+    let record = line_end.ignore_prev(csv_record.and_not(EOF));
+    let csv = csv_record
+        .prepend(record.many())
+        .ignore_this(line_end.opt());
+    csv.parse(stream)
+}
+
+/// An optional leading `+` or `-`: `-1` for `-`, `1` for `+` or when no sign
+/// is present. A lone `+` or `-` is still consumed as the sign, so whatever
+/// follows it must fail on its own.
+pub fn sign<'i>(stream: Stream<'i>) -> PResult<'i, i8> {
+    let sign = one_of("+-")
+        .opt()
+        .map(|sign| if sign == Some('-') { -1 } else { 1 });
+    sign.parse(stream)
+}
+
+/// Parses `sign` followed by `p`, negating `p`'s result after a `-`.
+pub fn signed<'i, P>(p: P) -> impl Parser<'i, Result = P::Result>
+where
+    P: Parser<'i>,
+    P::Result: Neg<Output = P::Result>,
+{
+    move |stream: Stream<'i>| {
+        let (s, sign) = sign.parse(stream)?;
+        let (s, value) = p.parse(s)?;
+        s.ok(if sign < 0 { -value } else { value })
+    }
+}
+
+fn parse_integer<'i, T: std::str::FromStr>(
+    stream: Stream<'i>,
+    p: impl Parser<'i>,
+) -> PResult<'i, T> {
+    let (s, text) = p.recognize().rule("integer").parse(stream.clone())?;
+    match text.parse() {
+        Ok(n) => s.ok(n),
+        Err(_) => stream.err_at(
+            stream.span_to(&s),
+            "integer literal too large".to_string().into(),
+        ),
+    }
+}
+
+/// A decimal integer with an optional `+` or `-`; a lone sign is rejected.
+pub fn integer<'i>(stream: Stream<'i>) -> PResult<'i, i64> {
+    parse_integer(stream, sign.seq(digits1))
+}
+
+pub fn uinteger<'i>(stream: Stream<'i>) -> PResult<'i, u64> {
+    parse_integer(stream, digits1)
+}
+
+/// An unsigned integer with a `0x`, `0o` or `0b` prefix. Try it before
+/// `uinteger`, which would stop at the leading `0`.
+pub fn radix_integer<'i>(stream: Stream<'i>) -> PResult<'i, u64> {
+    let (s, prefix) = '0'.ignore_prev(one_of("xob")).parse(stream.clone())?;
+    let (radix, result) = match prefix {
+        'x' => (16, hex_digits1(s)),
+        'o' => (
+            8,
+            slice_of_some(|c| ('0'..='7').contains(&c), "octal digits", s),
+        ),
+        _ => (
+            2,
+            slice_of_some(|c| c == '0' || c == '1', "binary digits", s),
+        ),
+    };
+    let (end, digits) = result?;
+    match u64::from_str_radix(digits, radix) {
+        Ok(n) => end.ok(n),
+        Err(_) => stream.err_at(
+            stream.span_to(&end),
+            "integer literal too large".to_string().into(),
+        ),
+    }
+}
+
+/// The pieces of a float literal, shared by `float` and `decimal` so they
+/// accept the same text.
+struct FloatParts<'i> {
+    negative: bool,
+    int_digits: &'i str,
+    frac_digits: &'i str,
+    exp: Option<&'i str>,
+}
+
+/// A mantissa such as `3`, `3.`, `3.14` or `.5` with an optional sign, then
+/// an optional exponent. An `e` must be followed by exponent digits, so `1e`
+/// is an error rather than `1`. `exp` keeps the exponent's sign.
+fn float_parts<'i>(name: &'static str) -> impl Parser<'i, Result = FloatParts<'i>> {
+    move |stream: Stream<'i>| {
+        let frac = '.'.ignore_prev(take_while(|c| c.is_ascii_digit()));
+        let int_part = digits1
+            .seq(frac.opt())
+            .map(|(int, frac)| (int, frac.unwrap_or("")));
+        let frac_only = '.'.ignore_prev(digits1).map(|frac| ("", frac));
+        let mantissa = sign.seq(int_part.or(frac_only));
+        let (s, (mantissa_sign, (int_digits, frac_digits))) = mantissa.rule(name).parse(stream)?;
+        let (end, exp) = match one_of("eE").parse(s.clone()) {
+            Ok((s, _)) => {
+                let (end, _) = sign.seq(digits1).rule("exponent").parse(s.clone())?;
+                (end.clone(), Some(s.slice_to(&end)))
+            }
+            Err(_) => (s, None),
+        };
+        let parts = FloatParts {
+            negative: mantissa_sign < 0,
+            int_digits,
+            frac_digits,
+            exp,
+        };
+        end.ok(parts)
+    }
+}
+
+/// A float such as `3`, `3.`, `3.14`, `.5`, `1e10` or `-2.5E-3`.
+pub fn float<'i>(stream: Stream<'i>) -> PResult<'i, f64> {
+    let (end, _) = float_parts("float").parse(stream.clone())?;
+    match stream.slice_to(&end).parse() {
+        Ok(value) => end.ok(value),
+        Err(err) => stream.err_at(stream.span_to(&end), format!("invalid float: {err}").into()),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Decimal<'i> {
+    pub negative: bool,
+    pub int_digits: &'i str,
+    pub frac_digits: &'i str,
+    pub exp: i32,
+}
+
+/// The digits of a float literal, kept as text so no precision is lost.
+/// Accepts the same text as `float`, so `int_digits` is empty for `.5` and
+/// `frac_digits` for `3.`.
+pub fn decimal<'i>(stream: Stream<'i>) -> PResult<'i, Decimal<'i>> {
+    let (end, parts) = float_parts("decimal").parse(stream)?;
+    let exp = match parts.exp {
+        None => 0,
+        Some(exp) => match exp.parse() {
+            Ok(exp) => exp,
+            Err(_) => {
+                let start = end.offset() - exp.len();
+                let span = Span {
+                    start,
+                    end: end.offset(),
+                };
+                return end.err_at(span, "exponent out of range".to_string().into());
+            }
+        },
+    };
+    let decimal = Decimal {
+        negative: parts.negative,
+        int_digits: parts.int_digits,
+        frac_digits: parts.frac_digits,
+        exp,
+    };
+    end.ok(decimal)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Date {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Time {
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+    pub nanosecond: u32,
+    pub offset_minutes: Option<i16>,
+}
+
+fn fixed_number<'i>(
+    digits: usize,
+    name: &'static str,
+    range: RangeInclusive<u32>,
+) -> impl Parser<'i, Result = u32> {
+    move |stream: Stream<'i>| {
+        let (mut s, mut value) = (stream.clone(), 0);
+        for _ in 0..digits {
+            let (next, digit) = ('0'..='9').parse(s)?;
+            value = value * 10 + digit.to_digit(10).unwrap();
+            s = next;
+        }
+        if range.contains(&value) {
+            s.ok(value)
+        } else {
+            let (start, end) = (range.start(), range.end());
+            stream.err(format!("{name} must be between {start} and {end}").into())
+        }
+    }
+}
+
+pub fn date<'i>(stream: Stream<'i>) -> PResult<'i, Date> {
+    let year = fixed_number(4, "year", 0..=9999);
+    let month = fixed_number(2, "month", 1..=12);
+    let day = fixed_number(2, "day", 1..=31);
+    let date = year.ignore_this('-').seq(month).ignore_this('-').seq(day);
+    let date = date.map(|((year, month), day)| Date {
+        year: year as u16,
+        month: month as u8,
+        day: day as u8,
+    });
+    date.parse(stream)
+}
+
+pub fn time<'i>(stream: Stream<'i>) -> PResult<'i, Time> {
+    let hour = fixed_number(2, "hour", 0..=23);
+    let minute = || fixed_number(2, "minute", 0..=59);
+    let second = fixed_number(2, "second", 0..=59);
+    let fraction = '.'.ignore_prev(digits1).map(|digits| {
+        let digits = &digits[..digits.len().min(9)];
+        digits.parse::<u32>().unwrap() * 10u32.pow(9 - digits.len() as u32)
+    });
+    let utc = 'Z'.value(0);
+    let offset = one_of("+-")
+        .seq(hour.clone())
+        .ignore_this(':')
+        .seq(minute());
+    let offset = offset.map(|((sign, hour), minute)| {
+        let minutes = (hour * 60 + minute) as i16;
+        if sign == '-' {
+            -minutes
+        } else {
+            minutes
+        }
+    });
+    let time = hour
+        .ignore_this(':')
+        .seq(minute())
+        .ignore_this(':')
+        .seq(second);
+    let time = time.seq(fraction.opt_default()).seq(utc.or(offset).opt());
+    let time = time.map(|((((hour, minute), second), nanosecond), offset)| Time {
+        hour: hour as u8,
+        minute: minute as u8,
+        second: second as u8,
+        nanosecond,
+        offset_minutes: offset,
+    });
+    time.parse(stream)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -87,4 +649,342 @@ mod tests {
             .map(|(_, r)| r);
         assert_eq!(result, Ok("Hello, world\n".to_string()));
     }
+
+    #[test]
+    fn parse_ident_except() {
+        let name = ident_except(&["if", "else"]);
+        let result = name.parse(Stream::new("iffy")).map(|(_, r)| r);
+        assert_eq!(result, Ok("iffy".to_string()));
+        let err = name.parse(Stream::new("if")).unwrap_err();
+        assert_eq!(err.message(), "unexpected keyword \"if\"");
+        assert_eq!(err.stream.rest_len(), 2);
+    }
+
+    #[test]
+    fn parse_words() {
+        let words = ws.ignore_prev(word).many().ignore_this(ws).ignore_this(EOF);
+        let text = r#"ls "a\tb" 'c\td' e\tf"#;
+        let (_, r) = words.parse(Stream::new(text)).unwrap();
+        assert_eq!(r, ["ls", "a\tb", "c\\td", "e\\tf"]);
+        assert!(word.parse(Stream::new("'open")).is_err());
+    }
+
+    #[test]
+    fn parse_escape_with() {
+        let percent = escape_with('%', &[('%', '%'), ('n', '\n')], false);
+        let (_, r) = percent.many().parse(Stream::new("%%%n")).unwrap();
+        assert_eq!(r, ['%', '\n']);
+        assert!(percent.parse(Stream::new("%x41")).is_err());
+        let (_, r) = escape.parse(Stream::new("\\x41")).unwrap();
+        assert_eq!(r, 'A');
+    }
+
+    #[test]
+    fn parse_strict_string() {
+        let result = strict_string.parse(Stream::new(r#""a\tb""#));
+        assert_eq!(result.map(|(_, r)| r), Ok("a\tb".to_string()));
+        let err = strict_string.parse(Stream::new("\"a\tb\"")).unwrap_err();
+        assert_eq!(err.stream.offset(), 2);
+        assert_eq!(
+            err.message(),
+            "unescaped control character U+0009 in string"
+        );
+        assert!(string.parse(Stream::new("\"a\tb\"")).is_ok());
+    }
+
+    #[test]
+    fn parse_byte_escape_string() {
+        let result = byte_escape_string.parse(Stream::new(r#""caf\xc3\xa9\n""#));
+        assert_eq!(result.map(|(_, r)| r), Ok("caf\u{e9}\n".to_string()));
+        let err = byte_escape_string
+            .parse(Stream::new(r#""\xc3!""#))
+            .unwrap_err();
+        assert_eq!(err.message(), "invalid UTF-8 in string");
+    }
+
+    #[test]
+    fn parse_delimited_fields() {
+        let tsv = delimited_field('\t', '"').list('\t');
+        let (_, r) = tsv.parse(Stream::new("a\t\"b\tc\"\t\r\n")).unwrap();
+        assert_eq!(r, ["a", "b\tc", ""]);
+        let semi = delimited_field(';', '\'').list(';');
+        let (s, r) = semi.parse(Stream::new("1,5;'it''s; ok';x\ny")).unwrap();
+        assert_eq!(r, ["1,5", "it's; ok", "x"]);
+        assert_eq!(s.rest_len(), 2);
+    }
+
+    #[test]
+    fn parse_keyword_set() {
+        let kw = keyword_set(&["for", "while", "loop"]);
+        let (_, r) = kw.parse(Stream::new("while x")).unwrap();
+        assert_eq!(r, "while");
+        let err = kw.parse(Stream::new("fro x")).unwrap_err();
+        let expected =
+            "1:1: unknown keyword `fro`\nfro x\n^\n1:1: note: did you mean `for`?\nfro x\n^^^";
+        assert_eq!(err.render(), expected);
+        let err = kw.parse(Stream::new("forever")).unwrap_err();
+        assert_eq!(err.message(), "expected <keyword>");
+        assert!(err.notes.is_empty());
+        let stmt = kw.seq(' '.ignore_prev(ident)).seq(';');
+        let p = stmt.many().ignore_this(EOF);
+        let err = p.parse(Stream::new("for x;fro y;")).unwrap_err();
+        assert_eq!(err.stream.offset(), 6);
+        assert_eq!(
+            err.notes,
+            [(Span { start: 6, end: 9 }, "did you mean `for`?".into())]
+        );
+    }
+
+    #[test]
+    fn parse_csv() {
+        let text = "a,\"b,c\"\r\n\"d\ne\",\"f\"\"g\",\n,h\n";
+        let result = csv
+            .ignore_this(EOF)
+            .parse(Stream::new(text))
+            .map(|(_, r)| r);
+        let expected = vec![
+            vec!["a".to_string(), "b,c".to_string()],
+            vec!["d\ne".to_string(), "f\"g".to_string(), "".to_string()],
+            vec!["".to_string(), "h".to_string()],
+        ];
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn parse_digit_slices() {
+        let text = "0123abcDEF+";
+        let (s, digits) = digits1.parse(Stream::new(text)).unwrap();
+        assert_eq!(digits, "0123");
+        assert_eq!(alpha1.parse(s).map(|(_, r)| r), Ok("abcDEF"));
+        assert_eq!(
+            hex_digits1.parse(Stream::new(text)).map(|(_, r)| r),
+            Ok("0123abcDEF")
+        );
+        let err = digits1.parse(Stream::new("+1")).unwrap_err();
+        assert_eq!(err.message(), "expected <digits>");
+    }
+
+    #[test]
+    fn parse_char_class() {
+        let (_, class) = char_class.parse(Stream::new("[a-z0-9_]")).unwrap();
+        assert!(!class.negated);
+        assert!(class.matches('q') && class.matches('5') && class.matches('_'));
+        assert!(!class.matches('-') && !class.matches('A'));
+        let (_, class) = char_class.parse(Stream::new("[^-a\\]]")).unwrap();
+        assert!(class.negated);
+        assert!(!class.matches('-') && !class.matches(']') && class.matches('b'));
+        let (_, class) = char_class.parse(Stream::new("[a-]")).unwrap();
+        assert_eq!(class.ranges, ['a'..='a', '-'..='-']);
+        let (s, r) = class.some().as_string().parse(Stream::new("a-b")).unwrap();
+        assert_eq!((s.offset(), r), (2, "a-".to_string()));
+        let err = char_class.parse(Stream::new("[z-a]")).unwrap_err();
+        assert_eq!(err.message(), "invalid range 'z'-'a'");
+        assert_eq!(err.stream.offset(), 1);
+        assert!(char_class.parse(Stream::new("[]")).is_err());
+    }
+
+    #[test]
+    fn parse_integers() {
+        let result = integer.parse(Stream::new("-42")).map(|(_, r)| r);
+        assert_eq!(result, Ok(-42));
+        let result = integer.parse(Stream::new("+7")).map(|(_, r)| r);
+        assert_eq!(result, Ok(7));
+        let min = i64::MIN.to_string();
+        let result = integer.parse(Stream::new(&min)).map(|(_, r)| r);
+        assert_eq!(result, Ok(i64::MIN));
+        let err = integer.parse(Stream::new("-x")).unwrap_err();
+        assert_eq!(err.message(), "expected <integer>");
+        let err = integer
+            .parse(Stream::new("9223372036854775808"))
+            .unwrap_err();
+        assert_eq!(err.message(), "integer literal too large");
+        let result = uinteger
+            .parse(Stream::new("18446744073709551615"))
+            .map(|(_, r)| r);
+        assert_eq!(result, Ok(u64::MAX));
+        assert!(uinteger.parse(Stream::new("-1")).is_err());
+    }
+
+    #[test]
+    fn parse_radix_integer() {
+        let literal = radix_integer.or(uinteger);
+        for (text, expected) in [("0x1F", 31), ("0o17", 15), ("0b1010", 10), ("12", 12)] {
+            let (s, r) = literal.parse(Stream::new(text)).unwrap();
+            assert_eq!((s.rest_len(), r), (0, expected));
+        }
+        let err = radix_integer.parse(Stream::new("0x")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (2, "expected <hex digits>".into())
+        );
+        let (s, r) = radix_integer.parse(Stream::new("0b102")).unwrap();
+        assert_eq!((s.offset(), r), (4, 2));
+        let err = radix_integer
+            .parse(Stream::new("0x10000000000000000"))
+            .unwrap_err();
+        assert_eq!(err.message(), "integer literal too large");
+    }
+
+    #[test]
+    fn parse_float() {
+        let cases = [
+            ("3", 3.0),
+            ("3.25", 3.25),
+            (".5", 0.5),
+            ("3.", 3.0),
+            ("1e10", 1e10),
+            ("-2.5E-3", -2.5e-3),
+        ];
+        for (text, expected) in cases {
+            let (s, r) = float.parse(Stream::new(text)).unwrap();
+            assert_eq!((s.rest_len(), r), (0, expected));
+        }
+        let err = float.parse(Stream::new(".")).unwrap_err();
+        assert_eq!(err.message(), "expected <float>");
+        let err = float.parse(Stream::new("1e")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (2, "expected <exponent>".into())
+        );
+    }
+
+    #[test]
+    fn parse_signed() {
+        let int = signed(digits1.map(|digits| digits.parse::<i64>().unwrap()));
+        let (_, r) = int.parse(Stream::new("-42")).unwrap();
+        assert_eq!(r, -42);
+        let (_, r) = int.parse(Stream::new("+7")).unwrap();
+        assert_eq!(r, 7);
+        let (_, r) = int.parse(Stream::new("7")).unwrap();
+        assert_eq!(r, 7);
+        let (s, r) = sign.parse(Stream::new("x")).unwrap();
+        assert_eq!((s.offset(), r), (0, 1));
+        let err = int.parse(Stream::new("+x")).unwrap_err();
+        assert_eq!(err.stream.offset(), 1);
+    }
+
+    #[test]
+    fn parse_comments() {
+        let (s, r) = line_comment("#").parse(Stream::new("# note\nx")).unwrap();
+        assert_eq!((s.offset(), r), (6, " note"));
+        let flat = block_comment("/*", "*/", false);
+        let nested = block_comment("/*", "*/", true);
+        let text = "/* a /* b */ c */";
+        let (s, r) = flat.parse(Stream::new(text)).unwrap();
+        assert_eq!((s.offset(), r), (12, " a /* b "));
+        let (s, r) = nested.parse(Stream::new(text)).unwrap();
+        assert_eq!((s.rest_len(), r), (0, " a /* b */ c "));
+        let err = nested.parse(Stream::new("/* a /* b */")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (0, "unterminated block comment".into())
+        );
+        let item = ws_and_comments(true).ignore_prev('x');
+        let text = " // one\n/* two /* three */ */\t// four\nx";
+        let (s, _) = item.parse(Stream::new(text)).unwrap();
+        assert_eq!(s.rest_len(), 0);
+        let (s, _) = ws_and_comments(false).parse(Stream::new("// end")).unwrap();
+        assert_eq!(s.rest_len(), 0);
+        let err = ws_and_comments(true)
+            .parse(Stream::new(" /* a /* b */ x"))
+            .unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (1, "unterminated block comment".into())
+        );
+    }
+
+    #[test]
+    fn parse_strip_bom() {
+        let p = strip_bom.ignore_prev("key");
+        let (s, _) = p.parse(Stream::new("\u{feff}key")).unwrap();
+        assert_eq!(s.rest_len(), 0);
+        let (s, _) = p.parse(Stream::new("key")).unwrap();
+        assert_eq!(s.rest_len(), 0);
+        assert!(Parser::parse(&"key", Stream::new("\u{feff}key")).is_err());
+    }
+
+    #[test]
+    fn parse_line_start() {
+        let heading = line_start.ignore_prev('#');
+        let hashes = heading.or(Any.map(|_| ' ')).many().as_string();
+        let result = hashes.parse(Stream::new("#a#\r\n#\r#\n\r\n#"));
+        assert_eq!(result.map(|(_, r)| r), Ok("#    # #   #".to_string()));
+    }
+
+    #[test]
+    fn parse_ident_with() {
+        let name = ident_with("-");
+        let result = name.parse(Stream::new("data-value")).map(|(_, r)| r);
+        assert_eq!(result, Ok("data-value".to_string()));
+        assert!(name.parse(Stream::new("-data")).is_err());
+        let (s, _) = ident.parse(Stream::new("data-value")).unwrap();
+        assert_eq!(s.rest_len(), 6);
+    }
+
+    #[test]
+    fn parse_interned_idents() {
+        let names = ws.ignore_prev(interned_ident).many();
+        let (s, r) = names.parse(Stream::new("a bc a bc d")).unwrap();
+        assert_eq!(r[0], r[2]);
+        assert_eq!(r[1], r[3]);
+        assert_ne!(r[0], r[1]);
+        assert_eq!(s.resolve(r[1]), "bc");
+        assert_eq!(s.resolve(r[4]), "d");
+    }
+
+    #[test]
+    fn parse_decimal() {
+        let (s, r) = decimal.parse(Stream::new("-12.0500e+3;")).unwrap();
+        let expected = Decimal {
+            negative: true,
+            int_digits: "12",
+            frac_digits: "0500",
+            exp: 3,
+        };
+        assert_eq!((s.rest_len(), r), (1, expected));
+        let (_, r) = decimal.parse(Stream::new("7")).unwrap();
+        assert_eq!((r.negative, r.frac_digits, r.exp), (false, "", 0));
+        let err = decimal.parse(Stream::new("1e99999999999")).unwrap_err();
+        assert_eq!(err.message(), "exponent out of range");
+        let (_, r) = decimal.parse(Stream::new(".5")).unwrap();
+        assert_eq!((r.int_digits, r.frac_digits), ("", "5"));
+        let (s, r) = decimal.parse(Stream::new("3.")).unwrap();
+        assert_eq!((s.rest_len(), r.int_digits, r.frac_digits), (0, "3", ""));
+        let err = decimal.parse(Stream::new("1e")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (2, "expected <exponent>".into())
+        );
+    }
+
+    #[test]
+    fn parse_date_time() {
+        let result = date.parse(Stream::new("2024-02-29")).map(|(_, r)| r);
+        let expected = Date {
+            year: 2024,
+            month: 2,
+            day: 29,
+        };
+        assert_eq!(result, Ok(expected));
+        let err = date.parse(Stream::new("2024-13-01")).unwrap_err();
+        assert_eq!(err.message(), "month must be between 1 and 12");
+        assert_eq!(err.stream.offset(), 5);
+
+        let result = time.parse(Stream::new("23:59:07.25-05:30")).map(|(_, r)| r);
+        let expected = Time {
+            hour: 23,
+            minute: 59,
+            second: 7,
+            nanosecond: 250_000_000,
+            offset_minutes: Some(-330),
+        };
+        assert_eq!(result, Ok(expected));
+        let result = time
+            .parse(Stream::new("00:00:00Z"))
+            .map(|(_, r)| r.offset_minutes);
+        assert_eq!(result, Ok(Some(0)));
+        let err = time.parse(Stream::new("24:00:00")).unwrap_err();
+        assert_eq!(err.message(), "hour must be between 0 and 23");
+    }
 }
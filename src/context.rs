@@ -1,42 +1,328 @@
-use super::{Error, ErrorMessage};
+use super::{Error, ErrorMessage, Span};
 use std::{
-    cell::RefCell,
-    collections::HashSet,
-    mem::{swap, take},
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    mem::{replace, take},
+    rc::Rc,
     str::Chars,
 };
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub(super) struct Context<'i> {
     pub text: &'i str,
+    pub text_len: usize,
     pub catcher: RefCell<Catcher<'i>>,
+    pub tokens: RefCell<Vec<(Span, &'static str)>>,
+    pub recovered: RefCell<Vec<Recovered<'i>>>,
+    pub symbols: RefCell<Interner<'i>>,
+    pub memo: RefCell<HashMap<MemoKey, Memo<'i>>>,
+    pub skip_ws: Cell<bool>,
+    pub cut: Cell<bool>,
+    pub state: RefCell<Box<dyn Any>>,
+    pub clone_state: Cell<CloneState>,
+    pub memo_log: RefCell<Vec<MemoKey>>,
+    pub deferred: RefCell<Vec<Deferred<'i>>>,
+    pub marks: Cell<usize>,
+    pub line_starts: RefCell<Option<Vec<usize>>>,
+    #[cfg(feature = "trace")]
+    pub depth: Cell<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+#[derive(Debug, Clone, Default)]
+pub(super) struct Interner<'i> {
+    ids: HashMap<&'i str, Symbol>,
+    names: Vec<&'i str>,
+}
+
+impl<'i> Interner<'i> {
+    pub fn intern(&mut self, name: &'i str) -> Symbol {
+        if let Some(&symbol) = self.ids.get(name) {
+            return symbol;
+        }
+        let symbol = Symbol(self.names.len() as u32);
+        self.names.push(name);
+        self.ids.insert(name, symbol);
+        symbol
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &'i str {
+        self.names[symbol.0 as usize]
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        for name in self.names.drain(len..) {
+            self.ids.remove(name);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Recovered<'i> {
+    pub chars: Chars<'i>,
+    pub messages: HashSet<ErrorMessage>,
+    pub notes: Vec<(Span, String)>,
+    pub span: Option<Span>,
+    pub code: Option<&'static str>,
+    pub committed: bool,
+}
+
+impl<'i> From<Error<'i>> for Recovered<'i> {
+    fn from(error: Error<'i>) -> Self {
+        Recovered {
+            chars: error.stream.chars,
+            messages: error.messages,
+            notes: error.notes,
+            span: error.span,
+            code: error.code,
+            committed: error.committed,
+        }
+    }
+}
+
+/// Rule name, offset, end of the window and whether whitespace is skipped:
+/// a rule's outcome at an offset also depends on the last two.
+pub(super) type MemoKey = (&'static str, usize, usize, bool);
+
+#[derive(Debug, Clone)]
+pub(super) struct Memo<'i> {
+    pub result: Result<(usize, Rc<dyn Any>), Recovered<'i>>,
+    pub trail: Trail<'i>,
+    pub cut: bool,
+}
+
+/// A callback held back until no enclosing combinator can backtrack past
+/// the parse that queued it.
+#[derive(Clone)]
+pub(super) struct Deferred<'i>(pub Rc<dyn Fn() + 'i>);
+
+impl std::fmt::Debug for Deferred<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Deferred")
+    }
+}
+
+/// What a parse appended to the logs a `Mark` rolls back, so a memoized
+/// rule can replay it.
+#[derive(Debug, Clone)]
+pub(super) struct Trail<'i> {
+    pub tokens: Vec<(Span, &'static str)>,
+    pub recovered: Vec<Recovered<'i>>,
+    pub deferred: Vec<Deferred<'i>>,
+}
+
+pub(super) type CloneState = fn(&dyn Any) -> Box<dyn Any>;
+
+pub(super) fn clone_state<S: Clone + 'static>(state: &dyn Any) -> Box<dyn Any> {
+    let state = state.downcast_ref::<S>().expect("state has another type");
+    Box::new(state.clone())
+}
+
+pub(super) struct Snapshot<'i> {
+    mark: Mark<'i>,
+    catcher: Catcher<'i>,
+    symbols: usize,
+    memo: usize,
+    state: Option<Box<dyn Any>>,
+}
+
+/// A point the token, recovered-error and deferred-callback logs can be
+/// reset to. While any mark is alive, deferred callbacks are held; dropping
+/// the last one runs them.
+pub(super) struct Mark<'i> {
+    ctx: Rc<Context<'i>>,
+    tokens: usize,
+    recovered: usize,
+    deferred: usize,
+}
+
+impl Drop for Mark<'_> {
+    fn drop(&mut self) {
+        let marks = self.ctx.marks.get() - 1;
+        self.ctx.marks.set(marks);
+        if marks == 0 {
+            let deferred = take(&mut *self.ctx.deferred.borrow_mut());
+            for callback in deferred {
+                (callback.0)();
+            }
+        }
+    }
 }
 
 impl<'i> Context<'i> {
     pub fn new(text: &'i str) -> Context<'i> {
         Context {
             text,
+            text_len: text.len(),
             catcher: Catcher::new(text.chars()).into(),
+            tokens: Vec::new().into(),
+            recovered: Vec::new().into(),
+            symbols: Interner::default().into(),
+            memo: HashMap::new().into(),
+            skip_ws: true.into(),
+            cut: false.into(),
+            state: RefCell::new(Box::new(())),
+            clone_state: Cell::new(clone_state::<()>),
+            memo_log: Vec::new().into(),
+            deferred: Vec::new().into(),
+            marks: 0.into(),
+            line_starts: None.into(),
+            #[cfg(feature = "trace")]
+            depth: 0.into(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn offset_of(&self, chars: &Chars<'i>) -> usize {
+        chars.as_str().as_ptr() as usize - self.text.as_ptr() as usize
+    }
+
+    /// 1-based line and column of `offset`. The line-start index is built on
+    /// the first query, so parses that never ask for positions don't pay
+    /// for it.
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let mut line_starts = self.line_starts.borrow_mut();
+        let line_starts = line_starts.get_or_insert_with(|| {
+            let bytes = self.text.as_bytes();
+            let mut starts = vec![0];
+            for (i, &b) in bytes.iter().enumerate() {
+                let is_break = b == b'\n' || (b == b'\r' && bytes.get(i + 1) != Some(&b'\n'));
+                if is_break {
+                    starts.push(i + 1);
+                }
+            }
+            starts
+        });
+        let line = line_starts.partition_point(|&start| start <= offset);
+        let before = &self.text[line_starts[line - 1]..offset];
+        if before.ends_with('\r') {
+            return (line + 1, 1);
+        }
+        (line, before.chars().count() + 1)
+    }
+
+    #[inline(always)]
+    pub fn mark(self: &Rc<Self>) -> Mark<'i> {
+        self.marks.set(self.marks.get() + 1);
+        Mark {
+            ctx: self.clone(),
+            tokens: self.tokens.borrow().len(),
+            recovered: self.recovered.borrow().len(),
+            deferred: self.deferred.borrow().len(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn reset(&self, mark: &Mark<'i>) {
+        self.tokens.borrow_mut().truncate(mark.tokens);
+        self.recovered.borrow_mut().truncate(mark.recovered);
+        self.deferred.borrow_mut().truncate(mark.deferred);
+    }
+
+    /// Runs `callback` now if nothing can backtrack past it, else queues it
+    /// for when the last `Mark` is dropped.
+    pub fn defer(&self, callback: Deferred<'i>) {
+        if self.marks.get() == 0 {
+            (callback.0)();
+        } else {
+            self.deferred.borrow_mut().push(callback);
+        }
+    }
+
+    /// Records everything a speculative parse can change, for `restore`.
+    /// The user state is skipped while it is mutably borrowed, as the parse
+    /// can't change it then.
+    pub fn snapshot(self: &Rc<Self>) -> Snapshot<'i> {
+        let clone_state = self.clone_state.get();
+        Snapshot {
+            mark: self.mark(),
+            catcher: self.catcher.borrow().clone(),
+            symbols: self.symbols.borrow().len(),
+            memo: self.memo_log.borrow().len(),
+            state: self
+                .state
+                .try_borrow()
+                .ok()
+                .map(|state| clone_state(&**state)),
+        }
+    }
+
+    pub fn restore(&self, snapshot: Snapshot<'i>) {
+        self.reset(&snapshot.mark);
+        *self.catcher.borrow_mut() = snapshot.catcher;
+        self.symbols.borrow_mut().truncate(snapshot.symbols);
+        let mut memo = self.memo.borrow_mut();
+        for key in self.memo_log.borrow_mut().drain(snapshot.memo..) {
+            memo.remove(&key);
+        }
+        if let (Some(state), Ok(mut current)) = (snapshot.state, self.state.try_borrow_mut()) {
+            *current = state;
+        }
+    }
+
+    pub fn replay(&self, trail: &Trail<'i>) {
+        self.tokens.borrow_mut().extend_from_slice(&trail.tokens);
+        self.recovered
+            .borrow_mut()
+            .extend_from_slice(&trail.recovered);
+        for callback in &trail.deferred {
+            self.defer(callback.clone());
+        }
+    }
+
+    pub fn since(&self, mark: &Mark<'i>) -> Trail<'i> {
+        Trail {
+            tokens: self.tokens.borrow()[mark.tokens..].to_vec(),
+            recovered: self.recovered.borrow()[mark.recovered..].to_vec(),
+            deferred: self.deferred.borrow()[mark.deferred..].to_vec(),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub(super) struct Catcher<'i> {
-    chars: Chars<'i>,
-    messages: HashSet<ErrorMessage>,
+    error: Recovered<'i>,
     pub is_started: bool,
 }
 
+fn empty_error(chars: Chars<'_>) -> Recovered<'_> {
+    Recovered {
+        chars,
+        messages: HashSet::new(),
+        notes: Vec::new(),
+        span: None,
+        code: None,
+        committed: false,
+    }
+}
+
 impl<'i> Catcher<'i> {
-    fn new(chars: Chars<'i>) -> Catcher<'i> {
+    pub fn new(chars: Chars<'i>) -> Catcher<'i> {
         Catcher {
-            chars,
-            messages: HashSet::new(),
+            error: empty_error(chars),
             is_started: true,
         }
     }
 
+    #[cfg(feature = "trace")]
+    #[inline(always)]
+    pub fn chars(&self) -> &Chars<'i> {
+        &self.error.chars
+    }
+
+    pub fn unwindow(&mut self, text: &'i str) {
+        let chars = &mut self.error.chars;
+        let offset = chars.as_str().as_ptr() as usize - text.as_ptr() as usize;
+        *chars = text[offset..].chars();
+    }
+
     #[inline(always)]
     pub fn set_started(&mut self, is_started: bool) -> bool {
         let old = self.is_started;
@@ -44,14 +330,18 @@ impl<'i> Catcher<'i> {
         old
     }
 
-    pub fn pop_error(&mut self, mut start_chars: Chars<'i>) -> (Chars<'i>, HashSet<ErrorMessage>) {
-        let messages = take(&mut self.messages);
-        swap(&mut self.chars, &mut start_chars);
-        (start_chars, messages)
+    /// The caught error with its notes, span and code. Commitment belongs to
+    /// the failure being returned, so the catcher never holds it.
+    pub fn peek_error(&self) -> Recovered<'i> {
+        self.error.clone()
+    }
+
+    pub fn pop_error(&mut self, start_chars: Chars<'i>) -> Recovered<'i> {
+        replace(&mut self.error, empty_error(start_chars))
     }
 
     pub fn set_error(&mut self, err: Error<'i>) {
-        self.messages = err.messages;
-        self.chars = err.stream.chars;
+        self.error = err.into();
+        self.error.committed = false;
     }
 }
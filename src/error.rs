@@ -1,4 +1,4 @@
-use super::Stream;
+use super::{Span, Stream};
 use std::{
     collections::HashSet,
     fmt::{Debug, Display},
@@ -17,10 +17,13 @@ pub enum ErrorMessage {
 pub enum Expected {
     Char(char),
     Str(&'static str),
+    StrCi(&'static str),
     OneOf(&'static str),
+    NoneOf(&'static str),
     Range(Range<char>),
     RangeInclusive(RangeInclusive<char>),
     Rule(&'static str),
+    Named(&'static str),
 }
 
 impl From<String> for ErrorMessage {
@@ -35,6 +38,33 @@ impl From<Expected> for ErrorMessage {
     }
 }
 
+const CHAR_SET_LIMIT: usize = 8;
+
+fn char_set(chars: &str) -> String {
+    let mut chars: Vec<char> = chars.chars().collect();
+    chars.sort_unstable();
+    chars.dedup();
+    let mut items = vec![];
+    let mut start = 0;
+    while start < chars.len() {
+        let mut end = start;
+        while end + 1 < chars.len() && chars[end + 1] as u32 == chars[end] as u32 + 1 {
+            end += 1;
+        }
+        if end - start >= 2 {
+            items.push(format!("{:?}-{:?}", chars[start], chars[end]));
+        } else {
+            items.extend(chars[start..=end].iter().map(|c| format!("{c:?}")));
+        }
+        start = end + 1;
+    }
+    if items.len() > CHAR_SET_LIMIT {
+        items.truncate(CHAR_SET_LIMIT);
+        items.push("...".to_string());
+    }
+    format!("[{}]", items.join(", "))
+}
+
 impl Display for Expected {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -46,12 +76,13 @@ impl Display for Expected {
                 }
             }
             Expected::Str(s) => write!(f, "{s:?}"),
-            Expected::OneOf(v) => {
-                write!(f, "one of {:?}", v.chars().collect::<Vec<_>>())
-            }
+            Expected::StrCi(s) => write!(f, "{s:?} (any case)"),
+            Expected::OneOf(v) => write!(f, "one of {}", char_set(v)),
+            Expected::NoneOf(v) => write!(f, "none of {}", char_set(v)),
             Expected::Range(r) => write!(f, "{r:?}"),
             Expected::RangeInclusive(r) => write!(f, "{r:?}"),
             Expected::Rule(r) => write!(f, "<{r}>"),
+            Expected::Named(n) => write!(f, "{n}"),
         }
     }
 }
@@ -65,10 +96,33 @@ impl Display for ErrorMessage {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 pub struct Error<'i> {
     pub stream: Stream<'i>,
     pub messages: HashSet<ErrorMessage>,
+    pub notes: Vec<(Span, String)>,
+    pub span: Option<Span>,
+    /// Machine-readable kind of the error, like rustc's `E0412`, for tools
+    /// that group or filter diagnostics.
+    pub code: Option<&'static str>,
+    /// Set when the failure came after a `Parser::cut`: choices return the
+    /// error as is instead of backtracking into another alternative.
+    pub committed: bool,
+}
+
+impl PartialEq for Error<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.stream.offset() == other.stream.offset() && self.messages == other.messages
+    }
+}
+
+impl Eq for Error<'_> {}
+
+impl Hash for Error<'_> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.stream.offset().hash(state);
+        self.messages.len().hash(state);
+    }
 }
 
 impl<'i> Error<'i> {
@@ -79,9 +133,41 @@ impl<'i> Error<'i> {
         Error {
             stream,
             messages: set,
+            notes: Vec::new(),
+            span: None,
+            code: None,
+            committed: false,
         }
     }
 
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_note(mut self, span: Span, note: impl Into<String>) -> Self {
+        self.notes.push((span, note.into()));
+        self
+    }
+
+    pub fn expected_set(&self) -> impl Iterator<Item = &Expected> {
+        self.messages.iter().filter_map(|message| match message {
+            ErrorMessage::Expected(e) => Some(e),
+            _ => None,
+        })
+    }
+
+    pub fn has_expected(&self, expected: &Expected) -> bool {
+        self.expected_set().any(|e| e == expected)
+    }
+
+    pub fn text_messages(&self) -> impl Iterator<Item = &str> {
+        self.messages.iter().filter_map(|message| match message {
+            ErrorMessage::Text(s) => Some(s.as_str()),
+            _ => None,
+        })
+    }
+
     pub fn message(&self) -> String {
         let mut expected = Vec::with_capacity(self.messages.len());
         let mut other = Vec::with_capacity(self.messages.len());
@@ -103,18 +189,75 @@ impl<'i> Error<'i> {
         other.join(" | ")
     }
 
-    pub fn or(mut self, error: Error<'i>) -> Error<'i> {
-        if self.stream.rest_len() == error.stream.rest_len() {
+    pub fn render(&self) -> String {
+        let label = match self.code {
+            Some(code) => format!("[{code}] {}", self.message()),
+            None => self.message(),
+        };
+        self.render_at("", &label)
+    }
+
+    /// Like `render`, but with `filename:line:col: error: message` headers
+    /// as rustc and clang print them, or `error[code]: message` with a code.
+    pub fn render_with_source(&self, filename: &str) -> String {
+        let label = match self.code {
+            Some(code) => format!("error[{code}]: {}", self.message()),
+            None => format!("error: {}", self.message()),
+        };
+        self.render_at(&format!("{filename}:"), &label)
+    }
+
+    fn render_at(&self, prefix: &str, label: &str) -> String {
+        let mut result = match self.span {
+            Some(span) => self.span_snippet(span, prefix, label),
+            None => snippet(&self.stream, 1, prefix, label),
+        };
+        for (span, note) in self.notes.iter() {
+            result.push('\n');
+            result.push_str(&self.span_snippet(*span, prefix, &format!("note: {note}")));
+        }
+        result
+    }
+
+    fn span_snippet(&self, span: Span, prefix: &str, label: &str) -> String {
+        let start = self.stream.at(span.start);
+        let text = start.slice_to(&self.stream.at(span.end));
+        let width = text.chars().take_while(|c| !matches!(c, '\n' | '\r'));
+        snippet(&start, width.count(), prefix, label)
+    }
+
+    pub fn or(mut self, mut error: Error<'i>) -> Error<'i> {
+        if self.stream.offset() == error.stream.offset() {
             self.messages.extend(error.messages);
+            for note in error.notes {
+                if !self.notes.contains(&note) {
+                    self.notes.push(note);
+                }
+            }
+            self.span = self.span.or(error.span);
+            self.code = self.code.or(error.code);
+            self.committed |= error.committed;
             self
-        } else if self.stream.rest_len() < error.stream.rest_len() {
+        } else if self.stream.offset() > error.stream.offset() {
+            self.committed |= error.committed;
             self
         } else {
+            error.committed |= self.committed;
             error
         }
     }
 }
 
+fn snippet(stream: &Stream<'_>, width: usize, prefix: &str, label: &str) -> String {
+    let (line, column) = stream.line_col();
+    format!(
+        "{prefix}{line}:{column}: {label}\n{}\n{}{}",
+        stream.line_text(),
+        " ".repeat(column - 1),
+        "^".repeat(width.max(1))
+    )
+}
+
 impl Display for Error<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.message())
@@ -124,3 +267,93 @@ impl Display for Error<'_> {
 impl std::error::Error for Error<'_> {}
 
 pub type PResult<'i, R> = Result<(Stream<'i>, R), Error<'i>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_one_of() {
+        let alnum = "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789_";
+        assert_eq!(
+            Expected::OneOf(alnum).to_string(),
+            "one of ['0'-'9', 'A'-'Z', '_', 'a'-'z']"
+        );
+        assert_eq!(
+            Expected::OneOf(" \n\rab").to_string(),
+            "one of ['\\n', '\\r', ' ', 'a', 'b']"
+        );
+        assert_eq!(
+            Expected::OneOf("acegikmoqs").to_string(),
+            "one of ['a', 'c', 'e', 'g', 'i', 'k', 'm', 'o', ...]"
+        );
+    }
+
+    #[test]
+    fn render_notes() {
+        let stream = Stream::new("(a\n  b c");
+        let err = Error::new(stream.at(7), "expected ')'".to_string().into());
+        let err = err.with_note(Span { start: 0, end: 1 }, "opened here");
+        let err = err.with_note(Span { start: 5, end: 8 }, "inside this");
+        let expected = "2:5: expected ')'\n  b c\n    ^\n\
+                        1:1: note: opened here\n(a\n^\n\
+                        2:3: note: inside this\n  b c\n  ^^^";
+        assert_eq!(err.render(), expected);
+        let expected = "main.x:2:5: error: expected ')'\n  b c\n    ^\n\
+                        main.x:1:1: note: opened here\n(a\n^\n\
+                        main.x:2:3: note: inside this\n  b c\n  ^^^";
+        assert_eq!(err.render_with_source("main.x"), expected);
+    }
+
+    #[test]
+    fn partition_messages() {
+        let stream = Stream::new("x");
+        let mut err = Error::new(stream.clone(), Expected::Char('a').into());
+        err = err.or(Error::new(stream.clone(), "bad x".to_string().into()));
+        err = err.or(Error::new(stream, ErrorMessage::UnexpectedEOF));
+        assert!(err.has_expected(&Expected::Char('a')));
+        assert!(!err.has_expected(&Expected::Char('b')));
+        assert_eq!(err.expected_set().count(), 1);
+        assert_eq!(err.text_messages().collect::<Vec<_>>(), ["bad x"]);
+    }
+
+    #[test]
+    fn render_span() {
+        let stream = Stream::new("let x = (1 +;");
+        let mut err = Error::new(stream.at(12), "expected expression".to_string().into());
+        err.span = Some(Span { start: 8, end: 12 });
+        assert_eq!(
+            err.render(),
+            "1:9: expected expression\nlet x = (1 +;\n        ^^^^"
+        );
+    }
+
+    #[test]
+    fn line_col_mixed_breaks() {
+        let stream = Stream::new("ab\r\ncé\rd\ne");
+        let positions: Vec<_> = [0, 3, 4, 5, 8, 9, 10]
+            .into_iter()
+            .map(|offset| stream.at(offset).line_col())
+            .collect();
+        assert_eq!(
+            positions,
+            [(1, 1), (2, 1), (2, 1), (2, 2), (3, 1), (3, 2), (4, 1)]
+        );
+    }
+
+    #[test]
+    fn render_code() {
+        let stream = Stream::new("let x: Foo;");
+        let err = Error::new(stream.at(7), "unknown type `Foo`".to_string().into());
+        let err = err.with_code("E0412");
+        assert_eq!(err.code, Some("E0412"));
+        assert_eq!(
+            err.render(),
+            "1:8: [E0412] unknown type `Foo`\nlet x: Foo;\n       ^"
+        );
+        assert_eq!(
+            err.render_with_source("main.x"),
+            "main.x:1:8: error[E0412]: unknown type `Foo`\nlet x: Foo;\n       ^"
+        );
+    }
+}
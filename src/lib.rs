@@ -1,3 +1,6 @@
+// `Error` carries its position, messages, notes, span and code by value.
+#![allow(clippy::result_large_err)]
+
 pub mod common;
 mod context;
 mod error;
@@ -5,10 +8,14 @@ mod parser;
 mod parsers;
 mod stream;
 
+pub use context::Symbol;
 pub use error::{Error, ErrorMessage, Expected, PResult};
-pub use parser::Parser;
-pub use parsers::{one_of, Any, EOF};
-pub use stream::Stream;
+pub use parser::{Either, Parser, SepConfig, Trailing};
+pub use parsers::{
+    assert_deterministic, literal_ci, longest_of, none_of, one_of, satisfy, sequence, take_while,
+    take_while1, with_state, Any, BoxedParser, Named, EOF,
+};
+pub use stream::{Position, Span, Stream};
 
 #[macro_export]
 macro_rules! parser {
@@ -17,6 +24,26 @@ macro_rules! parser {
             $body.parse(stream)
         }
     };
+    ($name: ident ($($param: ident : $ty: ty),* $(,)?) -> $result: ty = $body: expr ) => {
+        fn $name<'i>($($param: $ty,)* stream: Stream<'i>) -> PResult<'i, $result> {
+            $body.parse(stream)
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! seq_map {
+    ($func: expr, $($parser: expr),+ $(,)?) => {
+        move |stream| {
+            let mut stream = stream;
+            let result = $func($({
+                let (s, r) = $parser.parse(stream)?;
+                stream = s;
+                r
+            }),+);
+            Stream::ok(&stream, result)
+        }
+    };
 }
 
 #[macro_export]
@@ -47,3 +74,84 @@ macro_rules! bin_op {
         )
     };
 }
+
+/// Like `bin_op!`, but folds from the right, so `a ** b ** c` becomes
+/// `a ** (b ** c)`. Levels of both macros can be nested in each other.
+#[macro_export]
+macro_rules! bin_op_right {
+    ($cons: tt, $result: ty, $primary: expr) => {
+        $primary
+    };
+    ($cons: tt, $result: ty, $op: expr, $next: expr) => {{
+        fn bin_op_right_parser<'i>(stream: Stream<'i>) -> PResult<'i, $result> {
+            $next
+                .seq($op.seq($next).many())
+                .map(|(first, rest)| {
+                    let mut operands = vec![first];
+                    let mut ops = Vec::with_capacity(rest.len());
+                    for (op, operand) in rest {
+                        ops.push(op);
+                        operands.push(operand);
+                    }
+                    let mut right = operands.pop().unwrap();
+                    while let (Some(op), Some(left)) = (ops.pop(), operands.pop()) {
+                        right = $cons!(left, op, right);
+                    }
+                    right
+                })
+                .parse(stream)
+        }
+        bin_op_right_parser
+    }};
+    ($cons: tt, $result: ty, $first: expr, $($rest: expr),*) => {
+        bin_op_right!(
+            $cons,
+            $result,
+            $first,
+            bin_op_right!($cons, $result, $($rest),*)
+        )
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seq_map_macro() {
+        let pair = seq_map!(|a, _, b| format!("{a}{b}"), 'a'..='z', '=', '0'..='9');
+        let result = pair.parse(Stream::new("x=1")).map(|(_, r)| r);
+        assert_eq!(result, Ok("x1".to_string()));
+    }
+
+    #[test]
+    fn bin_op_right_macro() {
+        macro_rules! cons {
+            ($left: expr, $op: expr, $right: expr) => {
+                format!("({} {} {})", $left, $op, $right)
+            };
+        }
+        let sum = bin_op!(
+            cons,
+            String,
+            one_of("+-"),
+            bin_op_right!(
+                cons,
+                String,
+                '^',
+                ('0'..='9').named("digit").map(String::from)
+            )
+        );
+        let result = sum.parse(Stream::new("1-2^3^4-5")).map(|(_, r)| r);
+        assert_eq!(result, Ok("((1 - (2 ^ (3 ^ 4))) - 5)".to_string()));
+    }
+
+    #[test]
+    fn parser_macro_with_params() {
+        parser!(repeated(ch: char, n: usize) -> String = ch.count(n).as_string());
+        parser!(pair -> (String, String) = (|s| repeated('a', 2, s)).seq(|s| repeated('b', 1, s)));
+        let result = pair.parse(Stream::new("aab")).map(|(_, r)| r);
+        assert_eq!(result, Ok(("aa".to_string(), "b".to_string())));
+        assert!(repeated('a', 3, Stream::new("aab")).is_err());
+    }
+}
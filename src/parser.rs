@@ -1,8 +1,73 @@
-use super::{Expected, PResult, Stream};
-use std::{fmt::Debug, ops::RangeBounds};
+use super::{
+    common::ws, context::Deferred, BoxedParser, Error, ErrorMessage, Expected, Named, PResult,
+    Span, Stream, EOF,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    hash::Hash,
+    ops::RangeBounds,
+    rc::Rc,
+};
 
 type CtxFn<'i, C, R> = (C, fn(C, Stream<'i>) -> PResult<'i, R>);
 
+/// Describes an item-count range for error messages, e.g. "between 2 and 4 items".
+fn count_range(range: &impl RangeBounds<usize>) -> String {
+    use std::ops::Bound;
+
+    let min = match range.start_bound() {
+        Bound::Included(&n) => n,
+        Bound::Excluded(&n) => n + 1,
+        Bound::Unbounded => 0,
+    };
+    let max = match range.end_bound() {
+        Bound::Included(&n) => Some(n),
+        Bound::Excluded(&n) => Some(n.saturating_sub(1)),
+        Bound::Unbounded => None,
+    };
+    let items = |n: usize| {
+        if n == 1 {
+            "1 item".to_string()
+        } else {
+            format!("{n} items")
+        }
+    };
+    match max {
+        Some(max) if max == min => format!("exactly {}", items(max)),
+        Some(max) if min == 0 => format!("at most {}", items(max)),
+        Some(max) => format!("between {min} and {max} items"),
+        None => format!("at least {}", items(min)),
+    }
+}
+
+/// Semantics of `Parser::separated`: at least `min` items unless the list is
+/// empty and `allow_empty` is set, with `trailing` deciding what happens to a
+/// separator that no item follows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SepConfig {
+    pub min: usize,
+    pub trailing: Trailing,
+    pub allow_empty: bool,
+}
+
+/// What `Parser::separated` does with a separator not followed by an item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Trailing {
+    /// Consume it as part of the list.
+    Allow,
+    /// End the list before it, leaving it unconsumed.
+    Leave,
+    /// Fail with the error of the missing item.
+    Forbid,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
 pub trait Parser<'i>: Clone {
     type Result;
 
@@ -14,16 +79,74 @@ pub trait Parser<'i>: Clone {
         other: P,
     ) -> CtxFn<'i, (Self, P), Self::Result> {
         let ctx = (self.clone(), other);
-        (ctx, |(p1, p2), stream| match p1.parse(stream.clone()) {
-            r @ Ok(_) => r,
-            Err(err1) => match p2.parse(stream) {
-                r @ Ok(_) => r,
-                Err(err2) => {
+        (ctx, |(p1, p2), stream| {
+            let mark = stream.mark();
+            let err1 = match stream.attempt(&p1) {
+                Err(err1) if !err1.committed => err1,
+                r => return r,
+            };
+            stream.reset(&mark);
+            match stream.attempt(&p2) {
+                Err(err2) if !err2.committed => {
                     let stream = err1.stream.clone();
                     let err = stream.catch(err1.or(err2));
                     Err(err)
                 }
-            },
+                r => r,
+            }
+        })
+    }
+
+    /// Like `or`, but a match of `self` that consumes nothing also tries
+    /// `other`. If `other` then fails, the empty match of `self` is the
+    /// result after all, with the tokens and side effects it recorded.
+    #[inline(always)]
+    fn or_nonempty<P: Parser<'i, Result = Self::Result>>(
+        &self,
+        other: P,
+    ) -> CtxFn<'i, (Self, P), Self::Result> {
+        let ctx = (self.clone(), other);
+        (ctx, |(p1, p2), stream| {
+            let mark = stream.mark();
+            match stream.attempt(&p1) {
+                Ok((s, r)) if s.offset() > stream.offset() => s.ok(r),
+                Ok((_, r)) => {
+                    let trail = stream.ctx.since(&mark);
+                    stream.reset(&mark);
+                    match stream.attempt(&p2) {
+                        Err(err) if !err.committed => {
+                            stream.reset(&mark);
+                            stream.ctx.replay(&trail);
+                            stream.catch(err);
+                            stream.ok(r)
+                        }
+                        r2 => r2,
+                    }
+                }
+                Err(err1) if err1.committed => Err(err1),
+                Err(err1) => {
+                    stream.reset(&mark);
+                    stream.attempt(&p2).map_err(|err2| {
+                        if err2.committed {
+                            return err2;
+                        }
+                        let stream = err1.stream.clone();
+                        stream.catch(err1.or(err2))
+                    })
+                }
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn or_either<P: Parser<'i>>(
+        &self,
+        other: P,
+    ) -> CtxFn<'i, (Self, P), Either<Self::Result, P::Result>> {
+        let ctx = (self.clone(), other);
+        (ctx, |(p1, p2), stream| {
+            let p = p1.map(Either::Left).or(p2.map(Either::Right));
+            p.parse(stream)
         })
     }
 
@@ -37,12 +160,58 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    #[inline(always)]
+    fn seq_map<P: Parser<'i>, R, F: Clone + Fn(Self::Result, P::Result) -> R>(
+        &self,
+        other: P,
+        func: F,
+    ) -> CtxFn<'i, (Self, P, F), R> {
+        let ctx = (self.clone(), other, func);
+        (ctx, |(p1, p2, func), stream| {
+            let (stream, r1) = p1.parse(stream)?;
+            let (stream, r2) = p2.parse(stream)?;
+            stream.ok(func(r1, r2))
+        })
+    }
+
+    /// Commits to the enclosing `or` or `opt` alternative once `self` matches:
+    /// a later failure in that alternative comes back with
+    /// `Error::committed` set, and every choice it passes through (`or`,
+    /// `opt`, `many`, `separated` and the like) returns it as is instead of
+    /// backtracking. Only the failure carries the commitment, so a successful
+    /// alternative doesn't affect parsers after the `or`.
+    #[inline(always)]
+    fn cut(&self) -> CtxFn<'i, Self, Self::Result> {
+        (self.clone(), |p, stream| {
+            let (s, r) = p.parse(stream)?;
+            s.ctx.cut.set(true);
+            s.ok(r)
+        })
+    }
+
+    /// Positive lookahead: returns the result of `self` without consuming
+    /// input. On success, tokens and errors recorded by `self` are dropped.
+    #[inline(always)]
+    fn peek(&self) -> CtxFn<'i, Self, Self::Result> {
+        (self.clone(), |p, stream| {
+            let mark = stream.mark();
+            let catcher = stream.ctx.catcher.borrow().clone();
+            let (_, r) = stream.attempt(&p)?;
+            stream.reset(&mark);
+            *stream.ctx.catcher.borrow_mut() = catcher;
+            stream.ok(r)
+        })
+    }
+
     #[inline(always)]
     fn and_not<P: Debug + Parser<'i>>(&self, other: P) -> CtxFn<'i, (Self, P), Self::Result> {
         let ctx = (self.clone(), other);
         (ctx, |(p1, p2), stream| {
             let (s, r) = p1.parse(stream.clone())?;
-            if p2.parse(stream.clone()).is_ok() {
+            let mark = stream.mark();
+            let matched = stream.attempt(&p2).is_ok();
+            stream.reset(&mark);
+            if matched {
                 stream.err(format!("unexpected {:?}", p2).into())
             } else {
                 s.ok(r)
@@ -50,6 +219,18 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    #[inline(always)]
+    fn when<G: Parser<'i>>(&self, guard: G) -> CtxFn<'i, (Self, G), Self::Result> {
+        let ctx = (self.clone(), guard);
+        (ctx, |(p, guard), stream| {
+            let mark = stream.mark();
+            let guarded = stream.attempt(&guard);
+            stream.reset(&mark);
+            guarded?;
+            p.parse(stream)
+        })
+    }
+
     #[inline(always)]
     fn prepend<P: Parser<'i, Result = Vec<Self::Result>>>(
         &self,
@@ -64,28 +245,114 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    #[inline(always)]
+    fn concat<T, P: Parser<'i, Result = Vec<T>>>(&self, other: P) -> CtxFn<'i, (Self, P), Vec<T>>
+    where
+        Self: Parser<'i, Result = Vec<T>>,
+    {
+        let ctx = (self.clone(), other);
+        (ctx, |(p1, p2), stream| {
+            let (stream, mut r1) = p1.parse(stream)?;
+            let (stream, r2) = p2.parse(stream)?;
+            r1.extend(r2);
+            stream.ok(r1)
+        })
+    }
+
     #[inline(always)]
     fn opt(&self) -> CtxFn<'i, Self, Option<Self::Result>> {
-        (self.clone(), |p, stream| match p.parse(stream.clone()) {
-            Ok((s, r)) => s.ok(Some(r)),
-            Err(err) => {
-                stream.catch(err);
-                stream.ok(None)
+        (self.clone(), |p, stream| {
+            let mark = stream.mark();
+            match stream.attempt(&p) {
+                Ok((s, r)) => s.ok(Some(r)),
+                Err(err) if err.committed => Err(err),
+                Err(err) => {
+                    stream.reset(&mark);
+                    stream.catch(err);
+                    stream.ok(None)
+                }
             }
         })
     }
 
     #[inline(always)]
     fn many(&self) -> CtxFn<'i, Self, Vec<Self::Result>> {
-        (self.clone(), |p, mut stream| {
+        (self.clone(), |p, stream| {
+            let push = |mut result: Vec<_>, r| {
+                result.push(r);
+                result
+            };
+            p.fold_many(Vec::new, push).parse(stream)
+        })
+    }
+
+    /// Like `many`, but maps each result together with its zero-based index.
+    #[inline(always)]
+    fn many_indexed<R, F: Clone + Fn(usize, Self::Result) -> R>(
+        &self,
+        func: F,
+    ) -> CtxFn<'i, (Self, F), Vec<R>> {
+        let ctx = (self.clone(), func);
+        (ctx, |(p, func), stream| {
+            let push = move |mut result: Vec<R>, r| {
+                result.push(func(result.len(), r));
+                result
+            };
+            p.fold_many(Vec::new, push).parse(stream)
+        })
+    }
+
+    /// Lazy counterpart of `many`: before each item tries `terminator`, stops
+    /// at its first match and consumes it. `many` is greedy, so for a comment
+    /// body `Any.many().ignore_this("*/")` swallows the `*/` and fails, while
+    /// `Any.many_lazy_till("*/")` stops at the first `*/`. Prefer `many` when
+    /// an item can never begin the terminator.
+    #[inline(always)]
+    fn many_lazy_till<T: Parser<'i>>(
+        &self,
+        terminator: T,
+    ) -> CtxFn<'i, (Self, T), Vec<Self::Result>> {
+        let ctx = (self.clone(), terminator);
+        (ctx, |(p, terminator), mut stream| {
+            let mut result = vec![];
+            loop {
+                let mark = stream.mark();
+                let end_err = match stream.attempt(&terminator) {
+                    Ok((s, _)) => return s.ok(result),
+                    Err(err) if err.committed => return Err(err),
+                    Err(err) => err,
+                };
+                stream.reset(&mark);
+                match stream.attempt(&p) {
+                    Ok((s, r)) => {
+                        stream = s;
+                        result.push(r);
+                    }
+                    Err(err) => return Err(end_err.or(err)),
+                }
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn many_committed(&self, min_progress: usize) -> CtxFn<'i, (Self, usize), Vec<Self::Result>> {
+        let ctx = (self.clone(), min_progress);
+        (ctx, |(p, min_progress), mut stream| {
             let mut result = vec![];
             loop {
-                match p.parse(stream.clone()) {
+                let mark = stream.mark();
+                match stream.attempt_isolated(&p) {
                     Ok((s, r)) => {
                         stream = s;
                         result.push(r);
                     }
+                    Err(err) if err.committed => return Err(err),
                     Err(err) => {
+                        let progress = err.stream.offset().saturating_sub(stream.offset());
+                        if progress > 0 && progress >= min_progress {
+                            return Err(stream.catch(err));
+                        }
+                        stream.reset(&mark);
                         stream.catch(err);
                         break;
                     }
@@ -95,6 +362,106 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    /// Fails with `msg` when `pred` rejects the parsed result. The error is
+    /// reported at the end of the consumed input rather than rolled back to
+    /// the start, so enclosing combinators see the input as consumed.
+    #[inline(always)]
+    fn verify_keep<F: Clone + Fn(&Self::Result) -> bool>(
+        &self,
+        pred: F,
+        msg: &'static str,
+    ) -> CtxFn<'i, (Self, F, &'static str), Self::Result> {
+        let ctx = (self.clone(), pred, msg);
+        (ctx, |(p, pred, msg), stream| {
+            let (s, r) = p.parse(stream)?;
+            if pred(&r) {
+                s.ok(r)
+            } else {
+                s.err(msg.to_string().into())
+            }
+        })
+    }
+
+    /// Fails at the start of `self`, dropping its tokens, when `pred` rejects
+    /// the parsed result.
+    #[inline(always)]
+    fn verify<F: Clone + Fn(&Self::Result) -> bool>(
+        &self,
+        pred: F,
+    ) -> CtxFn<'i, (Self, F), Self::Result> {
+        let ctx = (self.clone(), pred);
+        (ctx, |(p, pred), stream| {
+            let mark = stream.mark();
+            let (s, r) = p.parse(stream.clone())?;
+            if pred(&r) {
+                s.ok(r)
+            } else {
+                stream.reset(&mark);
+                let message = "invalid value".to_string();
+                stream.err_at(stream.span_to(&s), message.into())
+            }
+        })
+    }
+
+    /// Like `verify`, with the error message built from the rejected result.
+    #[inline(always)]
+    fn verify_with<F: Clone + Fn(&Self::Result) -> bool, M: Clone + Fn(&Self::Result) -> String>(
+        &self,
+        pred: F,
+        msg: M,
+    ) -> CtxFn<'i, (Self, F, M), Self::Result> {
+        let ctx = (self.clone(), pred, msg);
+        (ctx, |(p, pred, msg), stream| {
+            let mark = stream.mark();
+            let (s, r) = p.parse(stream.clone())?;
+            if pred(&r) {
+                s.ok(r)
+            } else {
+                stream.reset(&mark);
+                stream.err_at(stream.span_to(&s), msg(&r).into())
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn expect<V: Clone + Debug + PartialEq<Self::Result>>(
+        &self,
+        expected: V,
+        msg: &'static str,
+    ) -> CtxFn<'i, (Self, V, &'static str), Self::Result>
+    where
+        Self::Result: Debug,
+    {
+        let ctx = (self.clone(), expected, msg);
+        (ctx, |(p, expected, msg), stream| {
+            let mark = stream.mark();
+            let (s, r) = p.parse(stream.clone())?;
+            if expected == r {
+                s.ok(r)
+            } else {
+                stream.reset(&mark);
+                let message = format!("expected {msg} {expected:?}, found {r:?}");
+                stream.err_at(stream.span_to(&s), message.into())
+            }
+        })
+    }
+
+    /// Runs `self` exactly `n` times, failing at the first missing item. On
+    /// char ranges `Iterator::count` takes precedence, so call `Parser::count`.
+    #[inline(always)]
+    fn count(&self, n: usize) -> CtxFn<'i, (Self, usize), Vec<Self::Result>> {
+        let ctx = (self.clone(), n);
+        (ctx, |(p, n), mut stream| {
+            let mut result = Vec::with_capacity(n);
+            for _ in 0..n {
+                let (s, r) = p.parse(stream)?;
+                stream = s;
+                result.push(r);
+            }
+            stream.ok(result)
+        })
+    }
+
     #[inline(always)]
     fn in_range<R: Debug + Clone + RangeBounds<usize>>(
         &self,
@@ -106,11 +473,71 @@ pub trait Parser<'i>: Clone {
             if range.contains(&r.len()) {
                 s.ok(r)
             } else {
-                stream.err(format!("not in range {:?}", range).into())
+                let message = format!("expected {}, found {}", count_range(&range), r.len());
+                s.err(message.into())
             }
         })
     }
 
+    /// Runs `self`, then hands its result and the stream after it to `func`,
+    /// which can parse further or fail. For grammars where what follows
+    /// depends on a parsed value, like length-prefixed data.
+    #[inline(always)]
+    fn and_then<R, F: Clone + Fn(Self::Result, Stream<'i>) -> PResult<'i, R>>(
+        &self,
+        func: F,
+    ) -> CtxFn<'i, (Self, F), R> {
+        let ctx = (self.clone(), func);
+        (ctx, |(p, func), stream| {
+            let (s, r) = p.parse(stream)?;
+            func(r, s)
+        })
+    }
+
+    /// Replaces the result of `self` with a clone of `value`. The value is
+    /// shared behind an `Rc` and only cloned when `self` succeeds.
+    #[inline(always)]
+    fn value<V: Clone>(&self, value: V) -> CtxFn<'i, (Self, Rc<V>), V> {
+        let ctx = (self.clone(), Rc::new(value));
+        (ctx, |(p, value), stream| {
+            let (s, _) = p.parse(stream)?;
+            s.ok(V::clone(&value))
+        })
+    }
+
+    /// Transforms the error of `self` when it fails. The transformed error
+    /// also replaces the caught one, so enclosing `many` or `opt` don't bring
+    /// back the original messages.
+    #[inline(always)]
+    fn map_err<F: Clone + Fn(Error<'i>) -> Error<'i>>(
+        &self,
+        func: F,
+    ) -> CtxFn<'i, (Self, F), Self::Result> {
+        let ctx = (self.clone(), func);
+        (ctx, |(p, func), stream| {
+            p.parse(stream.clone()).map_err(|err| {
+                let committed = err.committed;
+                let mut err = func(err);
+                err.committed |= committed;
+                stream.recatch(&err);
+                err
+            })
+        })
+    }
+
+    /// Adds `msg` to the messages of the error of `self` when it fails.
+    #[inline(always)]
+    fn context(&self, msg: String) -> CtxFn<'i, (Self, String), Self::Result> {
+        let ctx = (self.clone(), msg);
+        (ctx, |(p, msg), stream| {
+            let add = move |mut err: Error<'i>| {
+                err.messages.insert(ErrorMessage::Text(msg.clone()));
+                err
+            };
+            p.map_err(add).parse(stream)
+        })
+    }
+
     #[inline(always)]
     fn map<R, F: Clone + Fn(Self::Result) -> R>(&self, func: F) -> CtxFn<'i, (Self, F), R> {
         let ctx = (self.clone(), func);
@@ -119,6 +546,38 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    /// Calls `func` with the result of every successful parse of `self` that
+    /// the parse keeps. The call waits until no enclosing combinator can
+    /// backtrack past `self`, so results an `or`, `opt` or `many` discards
+    /// never reach `func`, and `func` may run after the parsers following
+    /// `self`. The result is cloned to be held until then.
+    #[inline(always)]
+    fn inspect<F: Clone + Fn(&Self::Result) + 'i>(
+        &self,
+        func: F,
+    ) -> CtxFn<'i, (Self, F), Self::Result>
+    where
+        Self::Result: Clone + 'i,
+    {
+        let ctx = (self.clone(), func);
+        (ctx, |(p, func), stream| {
+            let (s, r) = p.parse(stream)?;
+            let value = r.clone();
+            s.ctx.defer(Deferred(Rc::new(move || func(&value))));
+            s.ok(r)
+        })
+    }
+
+    #[inline(always)]
+    fn map_into<T>(&self) -> CtxFn<'i, Self, T>
+    where
+        Self::Result: Into<T>,
+    {
+        (self.clone(), |p, stream| {
+            p.parse(stream).map(|(s, r)| (s, r.into()))
+        })
+    }
+
     #[inline(always)]
     fn as_string(&self) -> CtxFn<'i, Self, String>
     where
@@ -129,11 +588,128 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    /// Returns the input consumed by `self`, discarding its result.
+    #[inline(always)]
+    fn recognize(&self) -> CtxFn<'i, Self, &'i str> {
+        (self.clone(), |p, stream| {
+            let (s, _) = p.parse(stream.clone())?;
+            let slice = stream.slice_to(&s);
+            s.ok(slice)
+        })
+    }
+
+    #[inline(always)]
+    fn fold_chars<B: Clone, F: Clone + Fn(B, char) -> B>(
+        &self,
+        init: B,
+        func: F,
+    ) -> CtxFn<'i, (Self, B, F), B>
+    where
+        Self::Result: IntoIterator<Item = char>,
+    {
+        let ctx = (self.clone(), init, func);
+        (ctx, |(p, init, func), stream| {
+            p.parse(stream)
+                .map(|(s, r)| (s, r.into_iter().fold(init, func)))
+        })
+    }
+
+    /// Like `many`, but folds each result into an accumulator started with
+    /// `init()` instead of collecting a `Vec`.
+    #[inline(always)]
+    fn fold_many<B, I: Clone + Fn() -> B, F: Clone + Fn(B, Self::Result) -> B>(
+        &self,
+        init: I,
+        fold: F,
+    ) -> CtxFn<'i, (Self, I, F), B> {
+        let ctx = (self.clone(), init, fold);
+        (ctx, |(p, init, fold), mut stream| {
+            let mut acc = init();
+            loop {
+                let mark = stream.mark();
+                match stream.attempt(&p) {
+                    Ok((s, r)) => {
+                        stream = s;
+                        acc = fold(acc, r);
+                    }
+                    Err(err) if err.committed => return Err(err),
+                    Err(err) => {
+                        stream.reset(&mark);
+                        stream.catch(err);
+                        break;
+                    }
+                }
+            }
+            stream.ok(acc)
+        })
+    }
+
     #[inline(always)]
     fn some(&self) -> CtxFn<'i, Self, Vec<Self::Result>> {
         (self.clone(), |p, stream| p.prepend(p.many()).parse(stream))
     }
 
+    #[inline(always)]
+    fn prefix<O: Parser<'i>, F: Clone + Fn(O::Result, Self::Result) -> Self::Result>(
+        &self,
+        op: O,
+        build: F,
+    ) -> CtxFn<'i, (Self, O, F), Self::Result> {
+        let ctx = (self.clone(), op, build);
+        (ctx, |(p, op, build), stream| {
+            let (stream, (ops, r)) = op.many().seq(p).parse(stream)?;
+            stream.ok(ops.into_iter().rev().fold(r, |r, op| build(op, r)))
+        })
+    }
+
+    #[inline(always)]
+    fn non_assoc<
+        O: Parser<'i>,
+        F: Clone + Fn(Self::Result, O::Result, Self::Result) -> Self::Result,
+    >(
+        &self,
+        op: O,
+        build: F,
+    ) -> CtxFn<'i, (Self, O, F), Self::Result> {
+        let ctx = (self.clone(), op, build);
+        (ctx, |(p, op, build), stream| {
+            let (stream, (left, rest)) = p.seq(op.seq(p.clone()).opt()).parse(stream)?;
+            let Some((o, right)) = rest else {
+                return stream.ok(left);
+            };
+            let mark = stream.mark();
+            let chained = op.parse(stream.clone());
+            stream.reset(&mark);
+            if let Ok((end, _)) = chained {
+                let message = "non-associative operator cannot be chained".to_string();
+                return stream.err_at(stream.span_to(&end), message.into());
+            }
+            stream.ok(build(left, o, right))
+        })
+    }
+
+    #[inline(always)]
+    fn lexeme(&self) -> CtxFn<'i, Self, Self::Result> {
+        (self.clone(), |p, stream| {
+            let (s, r) = p.parse(stream)?;
+            if s.ctx.skip_ws.get() {
+                let (s, _) = ws(s)?;
+                return s.ok(r);
+            }
+            s.ok(r)
+        })
+    }
+
+    #[inline(always)]
+    fn raw(&self) -> CtxFn<'i, Self, Self::Result> {
+        (self.clone(), |p, stream| {
+            let skip_ws = stream.ctx.skip_ws.replace(false);
+            let result = p.parse(stream.clone());
+            stream.ctx.skip_ws.set(skip_ws);
+            result
+        })
+    }
+
     #[inline(always)]
     fn ignore_prev<P: Parser<'i>>(&self, other: P) -> CtxFn<'i, (Self, P), P::Result> {
         let ctx = (self.clone(), other);
@@ -154,50 +730,1541 @@ pub trait Parser<'i>: Clone {
         })
     }
 
+    /// Parses `self` and then requires `terminator`, consuming it. Meant as
+    /// the top-level "and then we must be done" assertion: with `EOF` as the
+    /// terminator trailing input fails with "expected EOF", and with
+    /// `EOF.or(';')` either end is accepted.
     #[inline(always)]
-    fn opt_default(&self) -> CtxFn<'i, Self, Self::Result>
-    where
-        Self::Result: Default,
-    {
-        (self.clone(), |p, stream| {
-            let (stream, r) = p.opt().parse(stream)?;
-            stream.ok(r.unwrap_or_default())
+    fn finished_by<T: Parser<'i>>(&self, terminator: T) -> CtxFn<'i, (Self, T), Self::Result> {
+        let ctx = (self.clone(), terminator);
+        (ctx, |(p, terminator), stream| {
+            let (s, r) = p.parse(stream)?;
+            let (s, _) = terminator.parse(s)?;
+            s.ok(r)
         })
     }
 
+    /// Parses `open`, `self` and `close`, returning only the result of `self`.
+    /// A missing `close` is reported where it was expected.
     #[inline(always)]
-    fn list<S: Parser<'i>>(&self, sep: S) -> CtxFn<'i, (Self, S), Vec<Self::Result>> {
-        let ctx = (self.clone(), sep);
-        (ctx, |(p, sep), stream| {
-            let list_parser = p.prepend(sep.ignore_prev(p.clone()).many());
-            list_parser.parse(stream)
+    fn delimited<O: Parser<'i>, C: Parser<'i>>(
+        &self,
+        open: O,
+        close: C,
+    ) -> CtxFn<'i, (Self, O, C), Self::Result> {
+        let ctx = (self.clone(), open, close);
+        (ctx, |(p, open, close), stream| {
+            let (s, _) = open.parse(stream)?;
+            let (s, r) = p.parse(s)?;
+            let (s, _) = close.parse(s)?;
+            s.ok(r)
         })
     }
 
     #[inline(always)]
-    fn list_trailing<S: Parser<'i>>(&self, sep: S) -> CtxFn<'i, (Self, S), Vec<Self::Result>> {
-        let ctx = (self.clone(), sep);
-        (ctx, |(p, sep), stream| {
-            let list_parser = p
-                .prepend(sep.ignore_prev(p.clone()).many())
-                .ignore_this(sep.opt());
-            list_parser.parse(stream)
+    fn line(&self) -> CtxFn<'i, Self, Self::Result> {
+        (self.clone(), |p, stream| {
+            let end = "\r\n".or("\n").or("\r").map(|_| ());
+            p.ignore_this(end.or(EOF.map(|_| ()))).parse(stream)
         })
     }
 
+    /// Recovers from a failure of `self` by inserting `synthetic` without
+    /// consuming input: the error is recorded in `Stream::recovered_errors`
+    /// and parsing goes on as if the missing input was present. This is the
+    /// counterpart of deletion recovery, which skips input up to a
+    /// synchronization point instead.
     #[inline(always)]
-    fn rule(&self, rule: &'static str) -> CtxFn<'i, (Self, &'static str), Self::Result> {
-        let ctx = (self.clone(), rule);
-        (ctx, |(p, rule), stream| {
-            let ctx = stream.ctx.clone();
-            let is_started = ctx.catcher.borrow_mut().set_started(false);
-            let result = p.parse(stream).map_err(|mut err| {
-                err.messages.clear();
-                err.messages.insert(Expected::Rule(rule).into());
-                err
-            });
-            ctx.catcher.borrow_mut().set_started(is_started);
-            result
-        })
+    fn expect_or_insert(
+        &self,
+        synthetic: Self::Result,
+    ) -> CtxFn<'i, (Self, Self::Result), Self::Result>
+    where
+        Self::Result: Clone,
+    {
+        let ctx = (self.clone(), synthetic);
+        (ctx, |(p, synthetic), stream| {
+            let mark = stream.mark();
+            match p.parse(stream.clone()) {
+                r @ Ok(_) => r,
+                Err(err) => {
+                    stream.reset(&mark);
+                    stream.recover(err);
+                    stream.ok(synthetic)
+                }
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn recover_in(
+        &self,
+        open: char,
+        close: char,
+        node: Self::Result,
+    ) -> CtxFn<'i, (Self, char, char, Self::Result), Self::Result>
+    where
+        Self::Result: Clone,
+    {
+        let ctx = (self.clone(), open, close, node);
+        (ctx, |(p, open, close, node), stream| {
+            let mark = stream.mark();
+            let err = match p.parse(stream.clone()) {
+                r @ Ok(_) => return r,
+                Err(err) => err,
+            };
+            stream.reset(&mark);
+            let (mut s, ch) = stream.next();
+            if ch != open {
+                return Err(err);
+            }
+            let mut depth = 1;
+            while s.rest_len() > 0 {
+                let (next, ch) = s.next();
+                s = next;
+                if ch == open {
+                    depth += 1;
+                } else if ch == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        stream.recover(err);
+                        return s.ok(node);
+                    }
+                }
+            }
+            Err(err)
+        })
+    }
+
+    /// Replaces a failure of `self` with the result of `func`, which gets the
+    /// error and the stream `self` started at. A successful fallback must not
+    /// return a stream before that start.
+    #[inline(always)]
+    fn result_or<F: Clone + Fn(Error<'i>, Stream<'i>) -> PResult<'i, Self::Result>>(
+        &self,
+        func: F,
+    ) -> CtxFn<'i, (Self, F), Self::Result> {
+        let ctx = (self.clone(), func);
+        (ctx, |(p, func), stream| {
+            let mark = stream.mark();
+            p.parse(stream.clone()).or_else(|err| {
+                stream.reset(&mark);
+                func(err, stream)
+            })
+        })
+    }
+
+    #[inline(always)]
+    fn opt_default(&self) -> CtxFn<'i, Self, Self::Result>
+    where
+        Self::Result: Default,
+    {
+        (self.clone(), |p, stream| {
+            let (stream, r) = p.opt().parse(stream)?;
+            stream.ok(r.unwrap_or_default())
+        })
+    }
+
+    #[inline(always)]
+    fn list<S: Parser<'i>>(&self, sep: S) -> CtxFn<'i, (Self, S), Vec<Self::Result>> {
+        let ctx = (self.clone(), sep);
+        (ctx, |(p, sep), stream| {
+            let config = SepConfig {
+                min: 1,
+                trailing: Trailing::Leave,
+                allow_empty: false,
+            };
+            p.separated(sep, config).parse(stream)
+        })
+    }
+
+    #[inline(always)]
+    fn list_trailing<S: Parser<'i>>(&self, sep: S) -> CtxFn<'i, (Self, S), Vec<Self::Result>> {
+        let ctx = (self.clone(), sep);
+        (ctx, |(p, sep), stream| {
+            let config = SepConfig {
+                min: 1,
+                trailing: Trailing::Allow,
+                allow_empty: false,
+            };
+            p.separated(sep, config).parse(stream)
+        })
+    }
+
+    /// Items separated by `sep`, possibly none. Unlike `list`, a separator
+    /// not followed by an item is an error rather than left unconsumed.
+    #[inline(always)]
+    fn sep_by<S: Parser<'i>>(&self, sep: S) -> CtxFn<'i, (Self, S), Vec<Self::Result>> {
+        let ctx = (self.clone(), sep);
+        (ctx, |(p, sep), stream| {
+            let config = SepConfig {
+                min: 0,
+                trailing: Trailing::Forbid,
+                allow_empty: true,
+            };
+            p.separated(sep, config).parse(stream)
+        })
+    }
+
+    /// `sep_by` requiring at least one item.
+    #[inline(always)]
+    fn sep_by1<S: Parser<'i>>(&self, sep: S) -> CtxFn<'i, (Self, S), Vec<Self::Result>> {
+        let ctx = (self.clone(), sep);
+        (ctx, |(p, sep), stream| {
+            let config = SepConfig {
+                min: 1,
+                trailing: Trailing::Forbid,
+                allow_empty: false,
+            };
+            p.separated(sep, config).parse(stream)
+        })
+    }
+
+    #[inline(always)]
+    fn sep_fold<S: Parser<'i>, B: Clone, F: Clone + Fn(B, Self::Result) -> B>(
+        &self,
+        sep: S,
+        init: B,
+        func: F,
+    ) -> CtxFn<'i, (Self, S, B, F), B> {
+        let ctx = (self.clone(), sep, init, func);
+        (ctx, |(p, sep, init, func), stream| {
+            let (s, first) = p.parse(stream)?;
+            let acc = func(init, first);
+            let rest = sep.ignore_prev(p).fold_many(move || acc.clone(), func);
+            rest.parse(s)
+        })
+    }
+
+    #[inline(always)]
+    fn separated<S: Parser<'i>>(
+        &self,
+        sep: S,
+        config: SepConfig,
+    ) -> CtxFn<'i, (Self, S, SepConfig), Vec<Self::Result>> {
+        let ctx = (self.clone(), sep, config);
+        (ctx, |(p, sep, config), stream| {
+            let mark = stream.mark();
+            let (mut stream, first) = match stream.attempt(&p) {
+                Ok(r) => r,
+                Err(err) if config.allow_empty && !err.committed => {
+                    stream.reset(&mark);
+                    stream.catch(err);
+                    return stream.ok(vec![]);
+                }
+                Err(err) => return Err(err),
+            };
+            let mut result = vec![first];
+            loop {
+                let mark = stream.mark();
+                let after_sep = match stream.attempt(&sep) {
+                    Ok((s, _)) => s,
+                    Err(err) if err.committed => return Err(err),
+                    Err(err) => {
+                        stream.reset(&mark);
+                        stream.catch(err);
+                        break;
+                    }
+                };
+                let sep_mark = after_sep.mark();
+                match after_sep.attempt(&p) {
+                    Ok((s, r)) => {
+                        stream = s;
+                        result.push(r);
+                    }
+                    Err(err) if err.committed => return Err(err),
+                    Err(err) => match config.trailing {
+                        Trailing::Allow => {
+                            after_sep.reset(&sep_mark);
+                            after_sep.catch(err);
+                            stream = after_sep;
+                            break;
+                        }
+                        Trailing::Leave => {
+                            stream.reset(&mark);
+                            stream.catch(err);
+                            break;
+                        }
+                        Trailing::Forbid => return Err(err),
+                    },
+                }
+            }
+            if result.len() < config.min {
+                let message = format!("expected at least {} items", config.min);
+                return stream.err(message.into());
+            }
+            stream.ok(result)
+        })
+    }
+
+    /// Collects `sep`-separated key-value entries into a map, failing on a
+    /// repeated key with a note at its first definition. Like `list`, it
+    /// needs at least one entry, so an empty block such as `{}` fails; wrap
+    /// it in `opt` to allow one.
+    #[inline(always)]
+    fn collect_map<K: Eq + Hash + Debug, V, S: Parser<'i>>(
+        &self,
+        sep: S,
+    ) -> CtxFn<'i, (Self, S), HashMap<K, V>>
+    where
+        Self: Parser<'i, Result = (K, V)>,
+    {
+        let ctx = (self.clone(), sep);
+        (ctx, |(p, sep), stream| {
+            let (s, entries) = p.spanned().list(sep).parse(stream)?;
+            let mut map: HashMap<K, (Span, V)> = HashMap::with_capacity(entries.len());
+            for (span, (key, value)) in entries {
+                if let Some((first, _)) = map.get(&key) {
+                    let first = *first;
+                    let message = format!("duplicate key {key:?}");
+                    let mut err = Error::new(s.at(span.start), message.into());
+                    err.span = Some(span);
+                    let err = err.with_note(first, "first defined here");
+                    s.recatch(&err);
+                    return Err(err);
+                }
+                map.insert(key, (span, value));
+            }
+            let map = map.into_iter().map(|(key, (_, value))| (key, value));
+            s.ok(map.collect())
+        })
+    }
+
+    /// Sets the span of a failure of `self` to run from where `self` started
+    /// to the error, so a report underlines the whole construct.
+    #[inline(always)]
+    fn widen_err(&self) -> CtxFn<'i, Self, Self::Result> {
+        (self.clone(), |p, stream| {
+            p.parse(stream.clone()).map_err(|mut err| {
+                if err.stream.offset() >= stream.offset() {
+                    err.span = Some(stream.span_to(&err.stream));
+                    stream.recatch(&err);
+                }
+                err
+            })
+        })
+    }
+
+    /// Like `map_err`, with `f` also given the input left at the error.
+    #[inline(always)]
+    fn explain<F: Clone + Fn(Error<'i>, &'i str) -> Error<'i>>(
+        &self,
+        f: F,
+    ) -> CtxFn<'i, (Self, F), Self::Result> {
+        let ctx = (self.clone(), f);
+        (ctx, |(p, f), stream| {
+            p.parse(stream.clone()).map_err(|err| {
+                let found = err.stream.remaining();
+                let committed = err.committed;
+                let mut err = f(err, found);
+                err.committed |= committed;
+                stream.recatch(&err);
+                err
+            })
+        })
+    }
+
+    /// Prints the rendered error to stderr when `self` fails at the furthest
+    /// position so far. Output needs `--features trace`; without it this is
+    /// a no-op.
+    #[inline(always)]
+    fn trace_on_error(&self, label: &'static str) -> CtxFn<'i, (Self, &'static str), Self::Result> {
+        let ctx = (self.clone(), label);
+        (ctx, |(p, label), stream| {
+            #[cfg(feature = "trace")]
+            {
+                let result = p.parse(stream);
+                if let Err(err) = &result {
+                    if err.stream.is_furthest_error() {
+                        eprintln!("{label}: {}", err.render());
+                    }
+                }
+                result
+            }
+            #[cfg(not(feature = "trace"))]
+            {
+                let _ = label;
+                p.parse(stream)
+            }
+        })
+    }
+
+    /// Prints each successful result of `self` to stderr with its span,
+    /// indented by nesting. Output needs `--features trace`; without it this
+    /// is a no-op.
+    #[inline(always)]
+    fn dbg_result(&self, label: &'static str) -> CtxFn<'i, (Self, &'static str), Self::Result>
+    where
+        Self::Result: Debug,
+    {
+        let ctx = (self.clone(), label);
+        (ctx, |(p, label), stream| {
+            #[cfg(feature = "trace")]
+            {
+                let depth = stream.ctx.depth.get();
+                stream.ctx.depth.set(depth + 1);
+                let result = p.parse(stream.clone());
+                stream.ctx.depth.set(depth);
+                if let Ok((s, r)) = &result {
+                    let Span { start, end } = stream.span_to(s);
+                    let indent = depth * 2;
+                    eprintln!("{:indent$}{label} [{start}..{end}] => {r:?}", "");
+                }
+                result
+            }
+            #[cfg(not(feature = "trace"))]
+            {
+                let _ = label;
+                p.parse(stream)
+            }
+        })
+    }
+
+    #[inline(always)]
+    fn spanned(&self) -> CtxFn<'i, Self, (Span, Self::Result)> {
+        (self.clone(), |p, stream| {
+            let (s, r) = p.parse(stream.clone())?;
+            let span = stream.span_to(&s);
+            s.ok((span, r))
+        })
+    }
+
+    #[inline(always)]
+    fn many_spanned(&self) -> CtxFn<'i, Self, Vec<(Span, Self::Result)>> {
+        (self.clone(), |p, stream| p.spanned().many().parse(stream))
+    }
+
+    /// `list` pairing each item with its span; separators are not included.
+    #[inline(always)]
+    #[allow(clippy::type_complexity)]
+    fn list_spanned<S: Parser<'i>>(
+        &self,
+        sep: S,
+    ) -> CtxFn<'i, (Self, S), Vec<(Span, Self::Result)>> {
+        let ctx = (self.clone(), sep);
+        (ctx, |(p, sep), stream| p.spanned().list(sep).parse(stream))
+    }
+
+    #[inline(always)]
+    fn token(&self, category: &'static str) -> CtxFn<'i, (Self, &'static str), Self::Result> {
+        let ctx = (self.clone(), category);
+        (ctx, |(p, category), stream| {
+            let (s, r) = p.parse(stream.clone())?;
+            let span = stream.span_to(&s);
+            stream.ctx.tokens.borrow_mut().push((span, category));
+            s.ok(r)
+        })
+    }
+
+    /// Returns what the parser would accept at the end of `stream`, for completion.
+    ///
+    /// The stream must end at the cursor: build it from the text before the
+    /// cursor with `Stream::new(&text[..cursor])`, on a fresh stream so no
+    /// earlier parse has left errors behind. If the parser gets stuck before
+    /// the cursor, the set is empty.
+    fn possible_next(&self, stream: Stream<'i>) -> HashSet<Expected> {
+        let err = match self.parse(stream.clone()) {
+            Ok((s, _)) => s.furthest_error(),
+            Err(err) => stream.catch(err),
+        };
+        if err.stream.rest_len() != 0 {
+            return HashSet::new();
+        }
+        err.expected_set().cloned().collect()
+    }
+
+    /// Reports whether `self` would succeed at `stream` without committing to
+    /// anything it did: collected tokens, recovered errors, the furthest
+    /// error, interned symbols, memo entries and the user state are all
+    /// rolled back.
+    fn would_match(&self, stream: &Stream<'i>) -> bool {
+        let snapshot = stream.ctx.snapshot();
+        let matched = stream.attempt(self).is_ok();
+        stream.ctx.restore(snapshot);
+        matched
+    }
+
+    #[inline(always)]
+    fn within(&self, len: usize) -> CtxFn<'i, (Self, usize), Self::Result> {
+        let ctx = (self.clone(), len);
+        (ctx, |(p, len), stream| {
+            let Some(window) = stream.window(len) else {
+                if stream.rest_len() >= len {
+                    let message = "window does not end on a char boundary".to_string();
+                    return stream.err(message.into());
+                }
+                let unit = if len == 1 { "byte" } else { "bytes" };
+                return stream.err(format!("expected {len} {unit} of input").into());
+            };
+            let result = p.parse(window).and_then(|(s, r)| {
+                if s.rest_len() == 0 {
+                    s.ok(r)
+                } else {
+                    s.err(Expected::Char(EOF).into())
+                }
+            });
+            match result {
+                Ok((s, r)) => s.unwindow().ok(r),
+                Err(mut err) => {
+                    err.stream = err.stream.unwindow();
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    fn run_collecting(&self, text: &'i str) -> (Option<Self::Result>, Vec<Error<'i>>) {
+        match self.parse(Stream::new(text)) {
+            Ok((s, r)) => (Some(r), s.recovered_errors()),
+            Err(err) => {
+                let mut errors = err.stream.recovered_errors();
+                errors.push(err);
+                (None, errors)
+            }
+        }
+    }
+
+    /// Erases the type of `self`, so parsers of different types can share a
+    /// `Vec` and long combinator chains get a nameable type.
+    #[inline(always)]
+    fn boxed(&self) -> BoxedParser<'i, Self::Result>
+    where
+        Self: 'i,
+    {
+        BoxedParser::new(self.clone())
+    }
+
+    #[inline(always)]
+    fn named(&self, name: &'static str) -> Named<Self> {
+        Named::new(self.clone(), name)
+    }
+
+    #[inline(always)]
+    fn rule(&self, rule: &'static str) -> CtxFn<'i, (Self, &'static str), Self::Result> {
+        let ctx = (self.clone(), rule);
+        (ctx, |(p, rule), stream| {
+            let ctx = stream.ctx.clone();
+            let is_started = ctx.catcher.borrow_mut().set_started(false);
+            let result = p.parse(stream).map_err(|mut err| {
+                err.messages.clear();
+                err.messages.insert(Expected::Rule(rule).into());
+                err
+            });
+            ctx.catcher.borrow_mut().set_started(is_started);
+            result
+        })
+    }
+
+    /// `rule` plus packrat memoization: the outcome at each position is
+    /// cached under `name`, so `name` must identify a single rule. Entries
+    /// are kept apart per `within` window and `raw` mode. The cache lives in
+    /// the parse's `Context`, so each `Stream::new` starts empty.
+    #[inline(always)]
+    fn rule_memo(&self, name: &'static str) -> CtxFn<'i, (Self, &'static str), Self::Result>
+    where
+        Self::Result: Clone + 'static,
+    {
+        let ctx = (self.clone(), name);
+        (ctx, |(p, name), stream| {
+            if let Some(result) = stream.memo_get(name) {
+                return result;
+            }
+            let mark = stream.mark();
+            let outer = stream.ctx.cut.replace(false);
+            let result = p.rule(name).parse(stream.clone());
+            let cut = stream.ctx.cut.get();
+            stream.ctx.cut.set(outer || cut);
+            stream.memo_put(name, &mark, cut, &result);
+            result
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::ident;
+    use crate::{
+        assert_deterministic, literal_ci, longest_of, none_of, one_of, satisfy, sequence,
+        take_while, take_while1, with_state, Any, ErrorMessage,
+    };
+
+    #[test]
+    fn many_committed_reports_bad_item() {
+        let item = ('a'..='z').ignore_this(';');
+        let text = "a;b;cd";
+        let (s, r) = item.many().parse(Stream::new(text)).unwrap();
+        assert_eq!((s.rest_len(), r), (2, vec!['a', 'b']));
+        let err = item.many_committed(1).parse(Stream::new(text)).unwrap_err();
+        assert_eq!(err.stream.offset(), 5);
+        assert_eq!(err.message(), "expected ';'");
+        let item = 'a'.seq('x'.seq('y').seq('z').opt());
+        let (s, r) = item.many_committed(1).parse(Stream::new("axyQ")).unwrap();
+        assert_eq!((s.offset(), r.len()), (1, 1));
+    }
+
+    #[test]
+    fn none_of_excludes_chars_and_eof() {
+        let (s, r) = none_of(",;")
+            .some()
+            .as_string()
+            .parse(Stream::new("ab;"))
+            .unwrap();
+        assert_eq!((s.offset(), r), (2, "ab".to_string()));
+        let err = none_of(",;").parse(Stream::new(";")).unwrap_err();
+        assert_eq!(err.message(), "expected none of [',', ';']");
+        assert!(none_of(",;").parse(Stream::new("")).is_err());
+    }
+
+    #[test]
+    fn take_while_slices() {
+        let digits = take_while(|c| c.is_ascii_digit());
+        let (s, r) = digits.parse(Stream::new("123ab")).unwrap();
+        assert_eq!((s.offset(), r), (3, "123"));
+        let (s, r) = digits.parse(Stream::new("ab")).unwrap();
+        assert_eq!((s.offset(), r), (0, ""));
+        let (s, r) = take_while(char::is_alphabetic)
+            .parse(Stream::new("été"))
+            .unwrap();
+        assert_eq!((s.rest_len(), r), (0, "été"));
+        let digits1 = take_while1(|c| c.is_ascii_digit());
+        let (_, r) = digits1.parse(Stream::new("7")).unwrap();
+        assert_eq!(r, "7");
+        let err = digits1.parse(Stream::new("x")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (0, "unexpected character".into())
+        );
+    }
+
+    #[test]
+    fn literal_ignoring_case() {
+        let select = literal_ci("select");
+        let (s, r) = select.parse(Stream::new("SeLeCt *")).unwrap();
+        assert_eq!((s.offset(), r), (6, "SeLeCt"));
+        let err = select.parse(Stream::new("sel")).unwrap_err();
+        assert_eq!(err.message(), "expected \"select\" (any case)");
+        assert!(literal_ci("é").parse(Stream::new("É")).is_err());
+    }
+
+    #[test]
+    fn delimited_reports_missing_close() {
+        let group = ('a'..='z').some().as_string().delimited('(', ')');
+        let (s, r) = group.parse(Stream::new("(ab)")).unwrap();
+        assert_eq!((s.rest_len(), r), (0, "ab".to_string()));
+        let err = group.parse(Stream::new("(ab]")).unwrap_err();
+        assert_eq!(err.stream.offset(), 3);
+        assert!(err.has_expected(&Expected::Char(')')));
+    }
+
+    #[test]
+    fn verify_rejects_keywords() {
+        let name = ident.verify(|name| name != "if");
+        let (_, r) = name.parse(Stream::new("iff")).unwrap();
+        assert_eq!(r, "iff");
+        let err = name.parse(Stream::new("if")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (0, "invalid value".into())
+        );
+        let name = ident.verify_with(|name| name != "if", |name| format!("{name:?} is reserved"));
+        let err = name.parse(Stream::new("if")).unwrap_err();
+        assert_eq!(err.message(), "\"if\" is reserved");
+        assert_eq!(err.span, Some(Span { start: 0, end: 2 }));
+        let digits = ('0'..='9').some().verify(|d| d.len() < 3);
+        let err = digits
+            .opt()
+            .seq('x')
+            .parse(Stream::new("1234"))
+            .unwrap_err();
+        assert_eq!(err.stream.offset(), 0);
+        assert!(err.text_messages().any(|m| m == "invalid value"));
+        let (s, _) = name
+            .or(ident)
+            .token("name")
+            .parse(Stream::new("if"))
+            .unwrap();
+        assert_eq!(s.tokens().len(), 1);
+    }
+
+    #[test]
+    fn verify_keep_fails_at_end() {
+        let digits = ('0'..='9').some();
+        let short = |d: &Vec<char>| d.len() < 3;
+        let err = digits.verify(short).parse(Stream::new("1234")).unwrap_err();
+        assert_eq!(err.stream.offset(), 0);
+        let kept = digits.verify_keep(short, "too many digits");
+        let err = kept.parse(Stream::new("1234")).unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+        assert!(err.text_messages().any(|m| m == "too many digits"));
+        let (s, r) = kept.parse(Stream::new("12")).unwrap();
+        assert_eq!((s.offset(), r), (2, vec!['1', '2']));
+    }
+
+    #[test]
+    fn result_or_falls_back() {
+        let stmt = ('a'..='z').some().as_string().ignore_this(';');
+        let lenient = stmt.result_or(|_, stream| {
+            let skip = take_while(|c| c != ';').ignore_this(';');
+            let (s, _) = skip.parse(stream)?;
+            s.ok("<error>".to_string())
+        });
+        let (s, r) = lenient.many().parse(Stream::new("ab;1x;cd;")).unwrap();
+        assert_eq!(
+            (s.rest_len(), r),
+            (0, vec!["ab".into(), "<error>".into(), "cd".into()])
+        );
+        let strict = stmt.result_or(|err, _| Err(err));
+        let err = strict.parse(Stream::new("1;")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (0, "expected 'a'..='z'".into())
+        );
+    }
+
+    #[test]
+    fn and_then_length_prefixed() {
+        let len = ('0'..='9').named("length");
+        let field = len.ignore_this(':').and_then(|len, s| {
+            let n = len.to_digit(10).unwrap() as usize;
+            Any.count(n).as_string().parse(s)
+        });
+        let (s, r) = field.parse(Stream::new("3:abcd")).unwrap();
+        assert_eq!((s.offset(), r), (5, "abc".to_string()));
+        let err = field.parse(Stream::new("3:ab")).unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+    }
+
+    #[test]
+    fn value_replaces_result() {
+        let yes = "yes".value(true).or("no".value(false));
+        let (_, r) = yes.parse(Stream::new("no")).unwrap();
+        assert!(!r);
+        let (s, r) = "nil"
+            .value(String::from("null"))
+            .parse(Stream::new("nil"))
+            .unwrap();
+        assert_eq!((s.offset(), r), (3, "null".to_string()));
+    }
+
+    #[test]
+    fn caught_error_keeps_notes() {
+        let stmt = 'a'.ignore_this(';').map_err(|mut err| {
+            err.span = Some(Span { start: 2, end: 3 });
+            err.with_code("E1")
+                .with_note(Span { start: 2, end: 2 }, "statement starts here")
+        });
+        let p = stmt.many().ignore_this(EOF);
+        let err = p.parse(Stream::new("a;a?")).unwrap_err();
+        assert_eq!(err.stream.offset(), 3);
+        assert_eq!(err.notes.len(), 1);
+        assert_eq!(err.span, Some(Span { start: 2, end: 3 }));
+        assert_eq!(err.code, Some("E1"));
+    }
+
+    #[test]
+    fn widen_err_spans_construct() {
+        let stmt = "let".seq(' ').seq(ident).seq('=').widen_err();
+        let p = stmt.ignore_this(';').many().ignore_this(EOF);
+        let err = p.parse(Stream::new("let x=;let y;")).unwrap_err();
+        assert_eq!(err.stream.offset(), 12);
+        assert_eq!(err.span, Some(Span { start: 7, end: 12 }));
+    }
+
+    #[test]
+    fn map_err_and_context() {
+        let close =
+            '}'.map_err(|err| Error::new(err.stream, "unterminated block".to_string().into()));
+        let err = '{'.seq(close).parse(Stream::new("{x")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (1, "unterminated block".into())
+        );
+        let err = close.opt().seq(';').parse(Stream::new("x")).unwrap_err();
+        assert_eq!(err.messages.len(), 2);
+        assert!(!err.has_expected(&Expected::Char('}')));
+        let err = ('a'..='z')
+            .context("in name".into())
+            .parse(Stream::new("1"))
+            .unwrap_err();
+        assert!(err.has_expected(&Expected::RangeInclusive('a'..='z')));
+        assert!(err.text_messages().any(|m| m == "in name"));
+    }
+
+    #[test]
+    fn satisfy_predicate() {
+        let alnum = satisfy(char::is_alphanumeric, Some("alphanumeric"));
+        let (s, r) = alnum.some().as_string().parse(Stream::new("é1-")).unwrap();
+        assert_eq!((s.offset(), r), (3, "é1".to_string()));
+        let err = alnum.parse(Stream::new("-")).unwrap_err();
+        assert_eq!(err.message(), "expected <alphanumeric>");
+        let err = satisfy(|c| c != 'x', None)
+            .parse(Stream::new(""))
+            .unwrap_err();
+        assert_eq!(err.message(), "unexpected character");
+    }
+
+    #[test]
+    fn count_exact() {
+        let digit = ('0'..='9').named("digit");
+        let (s, r) = digit.count(2).parse(Stream::new("123")).unwrap();
+        assert_eq!((s.offset(), r), (2, vec!['1', '2']));
+        let err = digit.count(3).parse(Stream::new("12x")).unwrap_err();
+        assert_eq!(err.stream.offset(), 2);
+        let (s, r) = 'a'.count(0).parse(Stream::new("a")).unwrap();
+        assert_eq!((s.offset(), r), (0, vec![]));
+    }
+
+    #[test]
+    fn in_range_reports_count() {
+        let digits = ('0'..='9').in_range(2..=4);
+        let err = digits.parse(Stream::new("1x")).unwrap_err();
+        assert_eq!(err.stream.offset(), 1);
+        assert!(err.messages.contains(&ErrorMessage::Text(
+            "expected between 2 and 4 items, found 1".into()
+        )));
+        let err = digits.parse(Stream::new("12345")).unwrap_err();
+        let text = "expected between 2 and 4 items, found 5";
+        assert!(err.messages.contains(&ErrorMessage::Text(text.into())));
+        let err = ('0'..='9')
+            .in_range(2..=2)
+            .parse(Stream::new("1"))
+            .unwrap_err();
+        let text = "expected exactly 2 items, found 1";
+        assert!(err.messages.contains(&ErrorMessage::Text(text.into())));
+        let err = ('0'..='9')
+            .in_range(..3)
+            .parse(Stream::new("123"))
+            .unwrap_err();
+        let text = "expected at most 2 items, found 3";
+        assert!(err.messages.contains(&ErrorMessage::Text(text.into())));
+    }
+
+    #[test]
+    fn finished_by_rejects_trailing_input() {
+        let num = ('0'..='9').some().as_string();
+        let (s, r) = num.finished_by(EOF).parse(Stream::new("42")).unwrap();
+        assert_eq!((s.rest_len(), r), (0, "42".to_string()));
+        let err = num.finished_by(EOF).parse(Stream::new("42x")).unwrap_err();
+        assert_eq!(err.stream.offset(), 2);
+        assert!(err.messages.contains(&Expected::Char(EOF).into()));
+        let stmt = num.finished_by(EOF.or(';'));
+        let (s, _) = stmt.parse(Stream::new("7;8")).unwrap();
+        assert_eq!(s.rest_len(), 1);
+        assert!(stmt.parse(Stream::new("7 ")).is_err());
+    }
+
+    #[test]
+    fn deterministic_parsers() {
+        assert_deterministic(('a'..='z').some().as_string(), "abc1");
+        assert_deterministic('a'.seq('b'), "ax");
+    }
+
+    #[test]
+    #[should_panic(expected = "parser is not deterministic")]
+    fn nondeterministic_parser() {
+        use std::{cell::Cell, rc::Rc};
+
+        let calls = Rc::new(Cell::new(0));
+        let counted = move |stream: Stream<'static>| {
+            calls.set(calls.get() + 1);
+            Any.in_range(..=calls.get()).parse(stream)
+        };
+        assert_deterministic(counted, "aaaa");
+    }
+
+    #[test]
+    fn many_indexed_numbers_items() {
+        let p = ('a'..='z').many_indexed(|i, ch| (i, ch));
+        let (s, r) = p.parse(Stream::new("ab1")).unwrap();
+        assert_eq!((s.offset(), r), (2, vec![(0, 'a'), (1, 'b')]));
+    }
+
+    #[test]
+    fn spans_of_repeated_items() {
+        let span = |start, end| Span { start, end };
+        let word = ('a'..='z').some().as_string();
+        let (_, r) = word
+            .ignore_this(' ')
+            .many_spanned()
+            .parse(Stream::new("ab c "))
+            .unwrap();
+        let expected = vec![
+            (span(0, 3), "ab".to_string()),
+            (span(3, 5), "c".to_string()),
+        ];
+        assert_eq!(r, expected);
+        let (_, r) = word.list_spanned(", ").parse(Stream::new("ab, c")).unwrap();
+        let expected = vec![
+            (span(0, 2), "ab".to_string()),
+            (span(4, 5), "c".to_string()),
+        ];
+        assert_eq!(r, expected);
+    }
+
+    #[test]
+    fn error_position() {
+        let p = "ab\r\n".ignore_prev("é".ignore_prev('x'));
+        let err = p.parse(Stream::new("ab\r\néy")).unwrap_err();
+        let position = err.stream.position();
+        assert_eq!((position.offset, position.line, position.column), (6, 2, 2));
+    }
+
+    #[test]
+    fn recognize_returns_slice() {
+        let number = ('0'..='9').some().seq(".".seq(('0'..='9').some()).opt());
+        let (s, r) = number.recognize().parse(Stream::new("12.5x")).unwrap();
+        assert_eq!((s.offset(), r), (4, "12.5"));
+        let (_, r) = number.recognize().parse(Stream::new("12.x")).unwrap();
+        assert_eq!(r, "12");
+        let (_, r) = 'a'.many().recognize().parse(Stream::new("b")).unwrap();
+        assert_eq!(r, "");
+    }
+
+    #[test]
+    fn boxed_alternatives() {
+        let parsers = vec![
+            "let".map(|_| 'l').boxed(),
+            ('0'..='9').boxed(),
+            'a'.ignore_prev('b').boxed(),
+        ];
+        let p = sequence(parsers);
+        let (_, r) = p.parse(Stream::new("let7ab")).unwrap();
+        assert_eq!(r, ['l', '7', 'b']);
+        assert!(p.clone().parse(Stream::new("let7b")).is_err());
+    }
+
+    #[test]
+    fn render_error_line() {
+        let p = "ab\r\nc".ignore_prev("dx");
+        let err = p.parse(Stream::new("ab\r\ncde\n")).unwrap_err();
+        assert_eq!(err.render(), "2:2: expected \"dx\"\ncde\n ^");
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn errors_equal_across_runs() {
+        use std::collections::HashSet;
+
+        let p = "ab".ignore_prev('c');
+        let errors: HashSet<_> = ["abd", "abe"]
+            .into_iter()
+            .map(|text| p.parse(Stream::new(text)).unwrap_err())
+            .collect();
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn concat_vecs() {
+        let p = 'a'.many().concat('b'.value('B').many());
+        let (s, r) = p.parse(Stream::new("aabbc")).unwrap();
+        assert_eq!((s.rest_len(), r), (1, vec!['a', 'a', 'B', 'B']));
+        let err = 'a'
+            .some()
+            .concat('b'.many())
+            .parse(Stream::new("b"))
+            .unwrap_err();
+        assert_eq!(err.message(), "expected 'a'");
+    }
+
+    #[test]
+    fn when_dispatches_on_lead() {
+        let num = ('0'..='9').some().as_string().when('0'..='9');
+        let word = ('a'..='z').some().as_string().when('a'..='z');
+        let item = num.or(word);
+        let (_, r) = item.parse(Stream::new("12a")).unwrap();
+        assert_eq!(r, "12");
+        let (_, r) = item.parse(Stream::new("ab1")).unwrap();
+        assert_eq!(r, "ab");
+        let err = 'a'.seq('b').when('a').parse(Stream::new("ac")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (1, "expected 'b'".into())
+        );
+    }
+
+    #[test]
+    fn possible_next_at_cursor() {
+        let kw = "let".or("fn").rule("keyword");
+        let stmt = kw.ignore_this(' ').seq(('a'..='z').some()).ignore_this(';');
+        let text = "let x";
+        let next = stmt.possible_next(Stream::new(&text[..4]));
+        assert_eq!(next, HashSet::from([Expected::RangeInclusive('a'..='z')]));
+        let next = stmt.possible_next(Stream::new(text));
+        let expected = [Expected::RangeInclusive('a'..='z'), Expected::Char(';')];
+        assert_eq!(next, HashSet::from(expected));
+        let next = stmt.possible_next(Stream::new(""));
+        assert_eq!(next, HashSet::from([Expected::Rule("keyword")]));
+        assert!(stmt.possible_next(Stream::new("if x")).is_empty());
+    }
+
+    #[test]
+    fn cut_commits_to_alternative() {
+        let cond = ('a'..='z').some();
+        let if_stmt = "if ".cut().ignore_prev(cond).map(|_| "if");
+        let call = ('a'..='z').some().ignore_this(" 1").map(|_| "call");
+        let stmt = if_stmt.or(call);
+        let err = stmt.parse(Stream::new("if 1")).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (3, "expected 'a'..='z'".into())
+        );
+        let err = if_stmt.opt().parse(Stream::new("if 1")).unwrap_err();
+        assert_eq!(err.stream.offset(), 3);
+        let (_, r) = if_stmt.opt().parse(Stream::new("x")).unwrap();
+        assert_eq!(r, None);
+        let (_, r) = stmt
+            .ignore_this(';')
+            .or("if x".value("raw"))
+            .parse(Stream::new("if x"))
+            .unwrap();
+        assert_eq!(r, "raw");
+    }
+
+    #[test]
+    fn cut_commitment_travels_with_error() {
+        let digit = '0'..='9';
+        let stmt = "if ".cut().ignore_prev(digit).or("while".value('w'));
+        let p = stmt.many().ignore_prev('x').or("if !z".value('z'));
+        let err = p.parse(Stream::new("if !z")).unwrap_err();
+        assert!(err.committed);
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (3, "expected '0'..='9'".into())
+        );
+        let recovered = 'a'.cut().ignore_prev('b').or('c').expect_or_insert('?');
+        let p = recovered.ignore_prev('x').or("az".value('z'));
+        let (_, r) = p.parse(Stream::new("az")).unwrap();
+        assert_eq!(r, 'z');
+    }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let p = ('a'..='z').some().peek().seq(Any.some().as_string());
+        let (s, (peeked, rest)) = p.parse(Stream::new("ab1")).unwrap();
+        assert_eq!(
+            (s.offset(), peeked, rest),
+            (3, vec!['a', 'b'], "ab1".into())
+        );
+        let err = 'a'.peek().parse(Stream::new("b")).unwrap_err();
+        assert_eq!(err.message(), "expected 'a'");
+        let stream = Stream::new("ab;");
+        let err = ('a'..='z')
+            .some()
+            .peek()
+            .seq('x')
+            .parse(stream)
+            .unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (0, "expected 'x'".into())
+        );
+    }
+
+    #[test]
+    fn would_match_leaves_no_trace() {
+        let call = ('a'..='z').some().token("name").seq('(');
+        let stream = Stream::new("f[x]");
+        assert!(!call.would_match(&stream));
+        assert!(('a'..='z').token("name").would_match(&stream));
+        assert!(stream.tokens().is_empty());
+        let err = 'g'.parse(stream).unwrap_err();
+        assert_eq!(
+            (err.stream.offset(), err.message()),
+            (0, "expected 'g'".into())
+        );
+    }
+
+    #[test]
+    fn inspect_skips_backtracked_results() {
+        use std::cell::RefCell;
+
+        let seen = Rc::new(RefCell::new(vec![]));
+        let log = seen.clone();
+        let name = Parser::inspect(&('a'..='z'), move |c| log.borrow_mut().push(*c));
+        let stmt = name.ignore_this(';').or("b.".value('.'));
+        let (_, r) = stmt.many().parse(Stream::new("a;b.")).unwrap();
+        assert_eq!(r, ['a', '.']);
+        assert_eq!(*seen.borrow(), ['a']);
+        name.parse(Stream::new("z")).unwrap();
+        assert_eq!(*seen.borrow(), ['a', 'z']);
+    }
+
+    #[test]
+    fn would_match_rolls_back_state() {
+        fn counted<'i>(stream: Stream<'i>) -> PResult<'i, char> {
+            *stream.state_mut::<usize>() += 1;
+            stream.intern("counted");
+            'a'.parse(stream)
+        }
+        let p = counted.rule_memo("counted");
+        let stream = Stream::new_with_state("ab", 0usize);
+        assert!(p.would_match(&stream));
+        assert_eq!(*stream.state::<usize>(), 0);
+        assert_eq!(stream.intern("other"), Stream::new("").intern("other"));
+        let (s, _) = p.parse(stream).unwrap();
+        assert_eq!(*s.state::<usize>(), 1);
+    }
+
+    #[test]
+    fn named_describes_parser() {
+        let kw = "if".or("else").named("keyword");
+        let name = ('a'..='z').and_not(kw.clone());
+        let err = name.parse(Stream::new("if")).unwrap_err();
+        let unexpected = ErrorMessage::Text("unexpected keyword".into());
+        assert!(err.messages.contains(&unexpected));
+        let err = kw.seq('x').parse(Stream::new("elsey")).unwrap_err();
+        assert_eq!(err.message(), "expected 'x'");
+        let err = 'x'.named("an x").parse(Stream::new("y")).unwrap_err();
+        assert!(err.messages.contains(&Expected::Named("an x").into()));
+    }
+
+    #[test]
+    fn within_byte_window() {
+        let field = ('0'..='9').many().as_string().within(3);
+        let record = field.seq(field.clone()).ignore_this(EOF);
+        let (_, r) = record.parse(Stream::new("123456")).unwrap();
+        assert_eq!(r, ("123".to_string(), "456".to_string()));
+        let err = record.parse(Stream::new("12a456")).unwrap_err();
+        assert_eq!(err.stream.offset(), 2);
+        assert_eq!(err.stream.rest_len(), 4);
+        let err = field.parse(Stream::new("12")).unwrap_err();
+        assert_eq!(err.message(), "expected 3 bytes of input");
+        let (s, r) = "ab".or("a").within(1).parse(Stream::new("abc")).unwrap();
+        assert_eq!((s.rest_len(), r), (2, "a"));
+        let err = Any.many().within(1).parse(Stream::new("é")).unwrap_err();
+        assert_eq!(err.message(), "window does not end on a char boundary");
+        let err = Any.within(1).parse(Stream::new("")).unwrap_err();
+        assert_eq!(err.message(), "expected 1 byte of input");
+    }
+
+    #[test]
+    fn dbg_result_passes_through() {
+        let digit = ('0'..='9').dbg_result("digit");
+        let num = digit.some().as_string().dbg_result("num");
+        let (_, r) = num.parse(Stream::new("42;")).unwrap();
+        assert_eq!(r, "42");
+        assert!(num.parse(Stream::new(";")).is_err());
+    }
+
+    #[test]
+    fn explain_with_found_text() {
+        let spec = '%'.ignore_prev(one_of("sd")).explain(|mut err, found| {
+            if let Some(c) = found.chars().next().filter(|c| c.is_alphabetic()) {
+                err.messages.clear();
+                err.messages
+                    .insert(format!("unknown format specifier `%{c}`").into());
+            }
+            err
+        });
+        let err = spec.parse(Stream::new("%q")).unwrap_err();
+        assert_eq!(err.message(), "unknown format specifier `%q`");
+        let err = spec.parse(Stream::new("%")).unwrap_err();
+        assert_eq!(err.message(), "expected one of ['d', 's']");
+        let err = spec.opt().seq('y').parse(Stream::new("%q")).unwrap_err();
+        assert_eq!(err.message(), "unknown format specifier `%q`");
+    }
+
+    #[test]
+    fn many_lazy_till_first_terminator() {
+        let comment = "/*".ignore_prev(Any.many_lazy_till("*/")).as_string();
+        let (s, r) = comment.parse(Stream::new("/* a * b */ c */")).unwrap();
+        assert_eq!((s.rest_len(), r.as_str()), (5, " a * b "));
+        assert!(Any
+            .many()
+            .ignore_this("*/")
+            .parse(Stream::new("a */"))
+            .is_err());
+        let err = comment.parse(Stream::new("/* a")).unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+    }
+
+    #[test]
+    fn prefix_nests_right() {
+        let atom = 'x'.map(|c| c.to_string());
+        let expr = atom.prefix(one_of("-!"), |op, e| format!("({op}{e})"));
+        let result = expr.parse(Stream::new("-!-x")).map(|(_, r)| r);
+        assert_eq!(result, Ok("(-(!(-x)))".to_string()));
+        let result = expr.parse(Stream::new("x")).map(|(_, r)| r);
+        assert_eq!(result, Ok("x".to_string()));
+    }
+
+    #[test]
+    fn non_assoc_rejects_chains() {
+        let atom = one_of("abc").map(|c| c.to_string());
+        let cmp = atom.non_assoc("<", |l, op, r| format!("({l}{op}{r})"));
+        let (_, r) = cmp.parse(Stream::new("a<b")).unwrap();
+        assert_eq!(r, "(a<b)");
+        let (_, r) = cmp.parse(Stream::new("a")).unwrap();
+        assert_eq!(r, "a");
+        let err = cmp.parse(Stream::new("a<b<c")).unwrap_err();
+        assert_eq!(err.message(), "non-associative operator cannot be chained");
+        assert_eq!(err.stream.offset(), 3);
+    }
+
+    #[test]
+    fn lines_without_final_newline() {
+        let line = ('a'..='z').some().as_string().line();
+        let result = line
+            .many()
+            .ignore_this(EOF)
+            .parse(Stream::new("ab\ncd\r\nef"));
+        let result = result.map(|(_, r)| r);
+        let expected = vec!["ab", "cd", "ef"];
+        assert_eq!(result, Ok(expected.into_iter().map(String::from).collect()));
+    }
+
+    #[test]
+    fn collect_tokens() {
+        let name = ('a'..='z').some();
+        let number = ('0'..='9').some().token("number");
+        let call = name.token("ident").seq('(').seq(number).seq(')');
+        let expr = call
+            .token("call")
+            .map(|_| ())
+            .or(name.token("var").map(|_| ()));
+        let (s, _) = expr.list(' ').parse(Stream::new("f(1) g")).unwrap();
+        let span = |start, end| Span { start, end };
+        let expected = vec![
+            (span(0, 4), "call"),
+            (span(0, 1), "ident"),
+            (span(2, 3), "number"),
+            (span(5, 6), "var"),
+        ];
+        assert_eq!(s.tokens(), expected);
+    }
+
+    #[test]
+    fn map_into_newtype() {
+        #[derive(Debug, PartialEq)]
+        struct Digit(char);
+
+        impl From<char> for Digit {
+            fn from(value: char) -> Self {
+                Digit(value)
+            }
+        }
+
+        let result = ('0'..='9').map_into::<Digit>().parse(Stream::new("7"));
+        assert_eq!(result.map(|(_, r)| r), Ok(Digit('7')));
+    }
+
+    #[test]
+    fn insert_missing_token() {
+        let group = '('.ignore_prev('x').ignore_this(')'.expect_or_insert(')'));
+        let (s, _) = group.ignore_this(';').parse(Stream::new("(x;")).unwrap();
+        let errors = s.recovered_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].stream.offset(), 2);
+        assert_eq!(errors[0].message(), "expected ')'");
+    }
+
+    #[test]
+    fn recover_balanced_group() {
+        let args = '('.ignore_prev(('a'..='z').list(',')).ignore_this(')');
+        let call = args.recover_in('(', ')', vec![]);
+        let p = call.ignore_this(' ').seq(call.clone());
+        let (s, r) = p.parse(Stream::new("(a,(b c)) (d)")).unwrap();
+        assert_eq!(r, (vec![], vec!['d']));
+        let errors = s.recovered_errors();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].stream.offset(), 3);
+        assert!(call.parse(Stream::new("(a,(b)")).is_err());
+    }
+
+    #[test]
+    fn run_collecting_batch() {
+        let args = '('.ignore_prev(('a'..='z').list(',')).ignore_this(')');
+        let p = args.recover_in('(', ')', vec![]).many().ignore_this(EOF);
+        let mut report = vec![];
+        for (file, text) in [("ok", "(a)"), ("bad", "(1)(b)(2"), ("worse", "(a)(1")] {
+            let (_, errors) = p.run_collecting(text);
+            report.extend(errors.iter().map(|err| format!("{file}:{}", err.render())));
+        }
+        assert_eq!(report.len(), 3);
+        assert!(report[0].starts_with("bad:1:2: "));
+        assert!(report[2].starts_with("worse:1:5: "));
+    }
+
+    #[test]
+    fn expect_magic_value() {
+        let version = ('0'..='9').or('.').some().as_string();
+        let header = version.expect("1.0", "version");
+        let (_, r) = header.parse(Stream::new("1.0")).unwrap();
+        assert_eq!(r, "1.0");
+        let err = "v"
+            .ignore_prev(header.clone())
+            .parse(Stream::new("v2.0"))
+            .unwrap_err();
+        assert_eq!(err.message(), "expected version \"1.0\", found \"2.0\"");
+        assert_eq!(err.stream.offset(), 1);
+        let either = header.or(version.map(|v| format!("old {v}")));
+        let (_, r) = either.parse(Stream::new("0.9")).unwrap();
+        assert_eq!(r, "old 0.9");
+    }
+
+    #[test]
+    fn rule_memo_reuses_results() {
+        use std::cell::Cell;
+
+        thread_local!(static CALLS: Cell<usize> = const { Cell::new(0) });
+        fn num<'i>(stream: Stream<'i>) -> PResult<'i, String> {
+            CALLS.with(|calls| calls.set(calls.get() + 1));
+            ('0'..='9').some().as_string().token("num").parse(stream)
+        }
+        let num = num.rule_memo("num");
+        let p = num
+            .ignore_this('+')
+            .seq(num)
+            .or(num.map(|n| (n, String::new())));
+        let stream = Stream::new("12");
+        let (s, r) = p.parse(stream).unwrap();
+        assert_eq!(r, ("12".to_string(), String::new()));
+        assert_eq!(CALLS.with(Cell::get), 1);
+        assert_eq!(s.tokens().len(), 1);
+        let err = num.parse(Stream::new("x")).unwrap_err();
+        assert_eq!(err.message(), "expected <num>");
+        assert_eq!(CALLS.with(Cell::get), 2);
+    }
+
+    #[test]
+    fn rule_memo_respects_window() {
+        let ab = "ab".rule_memo("ab");
+        let p = ab.peek().ignore_prev(ab.within(1).or("a".value("a")));
+        let (s, r) = p.parse(Stream::new("abc")).unwrap();
+        assert_eq!((s.offset(), r), (1, "a"));
+    }
+
+    #[test]
+    fn raw_disables_lexeme_ws() {
+        let word = ('a'..='z').some().as_string().lexeme();
+        let quoted = '"'.ignore_prev(word.many()).ignore_this('"');
+        let p = word.seq(quoted.lexeme()).seq(word.clone());
+        let (_, r) = p.parse(Stream::new("a \"b c \" d")).unwrap();
+        assert_eq!(r.0 .1, ["b", "c"]);
+        let p = word.seq(quoted.raw().lexeme()).seq(word);
+        let err = p.parse(Stream::new("a \"b c\" d")).unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+        let (_, r) = p.parse(Stream::new("a \"b\" d")).unwrap();
+        assert_eq!(r.1, "d");
+    }
+
+    #[test]
+    fn or_either_tags_branch() {
+        let num = ('0'..='9').some().as_string();
+        let word = ('a'..='z').some().as_string();
+        let p = num.or_either(word);
+        let (_, r) = p.parse(Stream::new("42")).unwrap();
+        assert_eq!(r, Either::Left("42".into()));
+        let (_, r) = p.parse(Stream::new("ab")).unwrap();
+        assert_eq!(r, Either::Right("ab".into()));
+        let err = p.parse(Stream::new("?")).unwrap_err();
+        assert_eq!(err.stream.offset(), 0);
+        assert!(err.has_expected(&Expected::RangeInclusive('0'..='9')));
+        assert!(err.has_expected(&Expected::RangeInclusive('a'..='z')));
+    }
+
+    #[test]
+    fn or_nonempty_skips_empty_match() {
+        let p = ('0'..='9')
+            .many()
+            .as_string()
+            .or_nonempty(('a'..='z').some().as_string());
+        let (_, r) = p.parse(Stream::new("abc")).unwrap();
+        assert_eq!(r, "abc");
+        let (_, r) = p.parse(Stream::new("12a")).unwrap();
+        assert_eq!(r, "12");
+        let (s, r) = p.parse(Stream::new("!")).unwrap();
+        assert_eq!((s.offset(), r.as_str()), (0, ""));
+        let p = 'x'.or_nonempty('y');
+        let err = p.parse(Stream::new("z")).unwrap_err();
+        assert!(err.has_expected(&Expected::Char('x')) && err.has_expected(&Expected::Char('y')));
+        let p = 'a'.many().token("as").or_nonempty('b'.map(|c| vec![c]));
+        let (s, r) = p.parse(Stream::new("!")).unwrap();
+        assert!(r.is_empty());
+        assert_eq!(s.tokens(), [(Span { start: 0, end: 0 }, "as")]);
+    }
+
+    #[test]
+    fn with_state_rejects_break_outside_loop() {
+        #[derive(Clone)]
+        struct Scope {
+            loops: usize,
+        }
+        fn stmt<'i>(stream: Stream<'i>) -> PResult<'i, &'static str> {
+            let brk = with_state(|scope: &Scope, stream: Stream<'i>| {
+                let (s, r) = Parser::parse(&"break", stream.clone())?;
+                if scope.loops == 0 {
+                    let message = "break outside of loop".to_string();
+                    return stream.err_at(stream.span_to(&s), message.into());
+                }
+                s.ok(r)
+            });
+            let body = |stream: Stream<'i>| {
+                stream.state_mut::<Scope>().loops += 1;
+                let result = '{'
+                    .ignore_prev(stmt.many())
+                    .ignore_this('}')
+                    .parse(stream.clone());
+                stream.state_mut::<Scope>().loops -= 1;
+                result
+            };
+            let loop_stmt = "loop".ignore_prev(body).map(|_| "loop");
+            loop_stmt.or(brk).ignore_this(';'.opt()).parse(stream)
+        }
+        let program = stmt.many().ignore_this(EOF);
+        let run = |text| program.parse(Stream::new_with_state(text, Scope { loops: 0 }));
+        let (_, r) = run("loop{loop{}break;}").unwrap();
+        assert_eq!(r, ["loop"]);
+        let err = run("loop{}break").unwrap_err();
+        assert_eq!(err.stream.offset(), 6);
+        assert_eq!(
+            err.text_messages().collect::<Vec<_>>(),
+            ["break outside of loop"]
+        );
+    }
+
+    #[test]
+    fn sep_fold_sums() {
+        let digit = Parser::map(&('0'..='9'), |c| c.to_digit(10).unwrap());
+        let sum = digit.sep_fold(',', 0, |acc, d| acc + d);
+        let (_, r) = sum.parse(Stream::new("1,2,3,4")).unwrap();
+        assert_eq!(r, 10);
+        let (s, r) = sum.parse(Stream::new("5,")).unwrap();
+        assert_eq!((s.rest_len(), r), (1, 5));
+        assert!(sum.parse(Stream::new("")).is_err());
+    }
+
+    #[test]
+    fn longest_of_maximal_munch() {
+        let op = longest_of(
+            vec!["<", "<<=", "<=", "<<"]
+                .into_iter()
+                .map(|op| op.token("op"))
+                .collect(),
+        );
+        let ops = op.many();
+        let (s, r) = ops.parse(Stream::new("<<=<<<=<")).unwrap();
+        assert_eq!(r, ["<<=", "<<", "<=", "<"]);
+        assert_eq!(s.tokens().len(), 4);
+        let err = op.parse(Stream::new("=")).unwrap_err();
+        assert_eq!(err.expected_set().count(), 4);
+    }
+
+    #[test]
+    fn sequence_reports_failed_item() {
+        let template = "Hello, {name}!";
+        let parts = ["Hello, ", "{name}", "!"];
+        let p = sequence(parts.map(|part| part.or("?")).to_vec());
+        let (_, r) = p.parse(Stream::new(template)).unwrap();
+        assert_eq!(r, parts);
+        let err = p.parse(Stream::new("Hello, ?.")).unwrap_err();
+        assert_eq!(err.stream.offset(), 8);
+        assert_eq!(
+            err.notes,
+            [(
+                Span { start: 8, end: 8 },
+                "sequence item 3 starts here".into()
+            )]
+        );
+    }
+
+    #[test]
+    fn separated_config() {
+        let config = SepConfig {
+            min: 2,
+            trailing: Trailing::Allow,
+            allow_empty: true,
+        };
+        let items = ('a'..='z').separated(',', config);
+        let parse = |text| {
+            items
+                .parse(Stream::new(text))
+                .map(|(s, r)| (s.rest_len(), r))
+        };
+        assert_eq!(parse(";"), Ok((1, vec![])));
+        assert_eq!(parse("a,b,;"), Ok((1, vec!['a', 'b'])));
+        assert!(parse("a,;").is_err());
+        let list = ('a'..='z').list(',');
+        let result = list
+            .parse(Stream::new("a,b,;"))
+            .map(|(s, r)| (s.rest_len(), r));
+        assert_eq!(result, Ok((2, vec!['a', 'b'])));
+        let config = SepConfig {
+            trailing: Trailing::Forbid,
+            ..config
+        };
+        let err = ('a'..='z')
+            .separated(',', config)
+            .parse(Stream::new("a,b,;"))
+            .map(|(s, r)| (s.rest_len(), r))
+            .unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+    }
+
+    #[test]
+    fn sep_by_lists() {
+        let items = ('a'..='z').sep_by(',');
+        let parse = |text| {
+            items
+                .parse(Stream::new(text))
+                .map(|(s, r)| (s.rest_len(), r))
+        };
+        assert_eq!(parse(";"), Ok((1, vec![])));
+        assert_eq!(parse("a,b;"), Ok((1, vec!['a', 'b'])));
+        let err = parse("a,b,;").unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+        let items1 = ('a'..='z').sep_by1(',');
+        let (_, r) = items1.parse(Stream::new("a")).unwrap();
+        assert_eq!(r, ['a']);
+        assert!(items1.parse(Stream::new(";")).is_err());
+    }
+
+    #[test]
+    fn fold_many_sums_digits() {
+        let digit = Parser::map(&('0'..='9'), |c| c.to_digit(10).unwrap());
+        let number = digit.fold_many(|| 0, |n, d| n * 10 + d);
+        let (s, r) = number.parse(Stream::new("1203x")).unwrap();
+        assert_eq!((s.offset(), r), (4, 1203));
+        let (s, r) = number.parse(Stream::new("x")).unwrap();
+        assert_eq!((s.offset(), r), (0, 0));
+    }
+
+    #[test]
+    fn fold_chars_collapses_spaces() {
+        let text = Any
+            .and_not('.')
+            .many()
+            .fold_chars(String::new(), |mut s, c| {
+                if !(c == ' ' && s.ends_with(' ')) {
+                    s.push(c);
+                }
+                s
+            });
+        let result = text.parse(Stream::new("a  b    c.")).map(|(_, r)| r);
+        assert_eq!(result, Ok("a b c".to_string()));
+    }
+
+    #[test]
+    fn collect_map_rejects_duplicates() {
+        let entry = ('a'..='z').ignore_this(':').seq('0'..='9');
+        let map = entry.collect_map(',');
+        let (_, r) = map.parse(Stream::new("a:1,b:2")).unwrap();
+        assert_eq!(r, HashMap::from([('a', '1'), ('b', '2')]));
+        let err = map.parse(Stream::new("a:1,b:2,a:3")).unwrap_err();
+        assert_eq!(err.message(), "duplicate key 'a'");
+        assert_eq!(err.stream.offset(), 8);
+        assert_eq!(
+            err.notes,
+            vec![(Span { start: 0, end: 3 }, "first defined here".into())]
+        );
+        let block = map.opt().seq(';');
+        let err = block.parse(Stream::new("a:1,a:2;")).unwrap_err();
+        assert_eq!(err.stream.offset(), 4);
+        assert_eq!(err.span, Some(Span { start: 4, end: 7 }));
+        assert_eq!(
+            err.notes,
+            [(Span { start: 0, end: 3 }, "first defined here".into())]
+        );
     }
 }
@@ -1,12 +1,19 @@
-use super::{parser::Parser, ErrorMessage, Expected, PResult, Stream};
+use super::{parser::Parser, Error, ErrorMessage, Expected, PResult, Stream};
+use std::fmt::Debug;
 use std::ops::{Range, RangeInclusive};
+use std::rc::Rc;
 
 impl<'i, R, F: Clone + Fn(Stream<'i>) -> PResult<'i, R>> Parser<'i> for F {
     type Result = R;
 
     #[inline(always)]
     fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
-        self(stream)
+        #[cfg(debug_assertions)]
+        let start = stream.clone();
+        let result = self(stream);
+        #[cfg(debug_assertions)]
+        start.debug_assert_advanced(&result);
+        result
     }
 }
 
@@ -16,7 +23,12 @@ impl<'i, R, C: Clone, F: Clone + Fn(C, Stream<'i>) -> PResult<'i, R>> Parser<'i>
     #[inline(always)]
     fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
         let (ctx, func) = self;
-        func(ctx.clone(), stream)
+        #[cfg(debug_assertions)]
+        let start = stream.clone();
+        let result = func(ctx.clone(), stream);
+        #[cfg(debug_assertions)]
+        start.debug_assert_advanced(&result);
+        result
     }
 }
 
@@ -36,19 +48,36 @@ impl<'i> Parser<'i> for char {
 impl<'i> Parser<'i> for &'static str {
     type Result = &'static str;
 
-    fn parse(&self, mut stream: Stream<'i>) -> PResult<'i, Self::Result> {
-        let start = stream.clone();
-        for ch in self.chars() {
-            let (s, c) = stream.next();
-            stream = s;
-            if ch != c {
-                return start.err(Expected::Str(*self).into());
+    fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
+        if stream.remaining().starts_with(self) {
+            stream.advance_bytes(self.len()).ok(self)
+        } else {
+            stream.err(Expected::Str(self).into())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LiteralCi(&'static str);
+
+impl<'i> Parser<'i> for LiteralCi {
+    type Result = &'i str;
+
+    fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
+        match stream.remaining().get(..self.0.len()) {
+            Some(prefix) if prefix.eq_ignore_ascii_case(self.0) => {
+                stream.advance_bytes(self.0.len()).ok(prefix)
             }
+            _ => stream.err(Expected::StrCi(self.0).into()),
         }
-        stream.ok(self)
     }
 }
 
+/// Matches `s` ignoring ASCII case and returns the input as written.
+pub fn literal_ci(s: &'static str) -> LiteralCi {
+    LiteralCi(s)
+}
+
 impl<'i> Parser<'i> for Range<char> {
     type Result = char;
 
@@ -75,7 +104,7 @@ impl<'i> Parser<'i> for RangeInclusive<char> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct OneOf(Vec<char>, &'static str);
 
 impl<'i> Parser<'i> for OneOf {
@@ -95,6 +124,207 @@ pub fn one_of(chars: &'static str) -> OneOf {
     OneOf(chars.chars().collect(), chars)
 }
 
+#[derive(Clone, Debug)]
+pub struct NoneOf(Vec<char>, &'static str);
+
+impl<'i> Parser<'i> for NoneOf {
+    type Result = char;
+
+    fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
+        let (end, ch) = stream.next();
+        if ch != EOF && !self.0.contains(&ch) {
+            end.ok(ch)
+        } else {
+            stream.err(Expected::NoneOf(self.1).into())
+        }
+    }
+}
+
+pub fn none_of(chars: &'static str) -> NoneOf {
+    NoneOf(chars.chars().collect(), chars)
+}
+
+/// Consumes one char accepted by `pred`. Errors read `expected <label>`, or
+/// `unexpected character` without a label.
+pub fn satisfy<'i, F: Clone + Fn(char) -> bool>(
+    pred: F,
+    label: Option<&'static str>,
+) -> impl Parser<'i, Result = char> {
+    move |stream: Stream<'i>| {
+        let (end, ch) = stream.next();
+        if ch != EOF && pred(ch) {
+            end.ok(ch)
+        } else if let Some(label) = label {
+            stream.err(Expected::Rule(label).into())
+        } else {
+            stream.err("unexpected character".to_string().into())
+        }
+    }
+}
+
+/// Consumes the longest run of chars accepted by `pred`, possibly empty, and
+/// returns it as a slice of the input.
+pub fn take_while<'i, F: Clone + Fn(char) -> bool>(pred: F) -> impl Parser<'i, Result = &'i str> {
+    move |stream: Stream<'i>| {
+        let rest = stream.remaining();
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        stream.advance_bytes(len).ok(&rest[..len])
+    }
+}
+
+/// Like `take_while`, but fails unless at least one char is accepted.
+pub fn take_while1<'i, F: Clone + Fn(char) -> bool>(pred: F) -> impl Parser<'i, Result = &'i str> {
+    let run = take_while(pred);
+    move |stream: Stream<'i>| {
+        let (s, r) = run.parse(stream.clone())?;
+        if r.is_empty() {
+            stream.err("unexpected character".to_string().into())
+        } else {
+            s.ok(r)
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Named<P> {
+    parser: P,
+    name: &'static str,
+}
+
+impl<P> Named<P> {
+    pub(super) fn new(parser: P, name: &'static str) -> Named<P> {
+        Named { parser, name }
+    }
+}
+
+impl<P> Debug for Named<P> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl<'i, P: Parser<'i>> Parser<'i> for Named<P> {
+    type Result = P::Result;
+
+    fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
+        self.parser.parse(stream.clone()).map_err(|mut err| {
+            if err.stream.same_position(&stream) {
+                err.messages.insert(Expected::Named(self.name).into());
+                err = stream.catch(err);
+            }
+            err
+        })
+    }
+}
+
+/// A type-erased parser, made by `Parser::boxed`. Cloning shares the parser.
+pub struct BoxedParser<'i, R>(Rc<dyn Fn(Stream<'i>) -> PResult<'i, R> + 'i>);
+
+impl<'i, R> BoxedParser<'i, R> {
+    pub(super) fn new<P: Parser<'i, Result = R> + 'i>(parser: P) -> BoxedParser<'i, R> {
+        BoxedParser(Rc::new(move |stream| parser.parse(stream)))
+    }
+}
+
+impl<R> Clone for BoxedParser<'_, R> {
+    fn clone(&self) -> Self {
+        BoxedParser(self.0.clone())
+    }
+}
+
+impl<'i, R> Parser<'i> for BoxedParser<'i, R> {
+    type Result = R;
+
+    #[inline(always)]
+    fn parse(&self, stream: Stream<'i>) -> PResult<'i, Self::Result> {
+        (self.0)(stream)
+    }
+}
+
+/// Maximal munch: runs every parser at the same position and keeps the one
+/// that consumed the most, the first on ties. That costs a run of each
+/// alternative per position, so use it for small lexer token sets such as
+/// `<`, `<=`, `<<` and `<<=`, and `or` elsewhere.
+pub fn longest_of<'i, P: Parser<'i>>(parsers: Vec<P>) -> impl Parser<'i, Result = P::Result> {
+    move |stream: Stream<'i>| {
+        let mark = stream.mark();
+        let mut best: Option<(Stream<'i>, P::Result, _)> = None;
+        let mut error: Option<Error<'i>> = None;
+        for p in &parsers {
+            match stream.attempt(p) {
+                Ok((s, r)) => {
+                    if best.as_ref().is_none_or(|(b, ..)| s.offset() > b.offset()) {
+                        best = Some((s, r, stream.ctx.since(&mark)));
+                    }
+                }
+                Err(err) if err.committed => return Err(err),
+                Err(err) => {
+                    error = Some(match error {
+                        Some(e) => e.or(err),
+                        None => err,
+                    })
+                }
+            }
+            stream.reset(&mark);
+        }
+        match best {
+            Some((s, r, trail)) => {
+                stream.ctx.replay(&trail);
+                if let Some(err) = error {
+                    stream.catch(err);
+                }
+                s.ok(r)
+            }
+            None => match error {
+                Some(err) => Err(stream.catch(err)),
+                None => stream.err("no alternatives".to_string().into()),
+            },
+        }
+    }
+}
+
+pub fn sequence<'i, P: Parser<'i>>(parsers: Vec<P>) -> impl Parser<'i, Result = Vec<P::Result>> {
+    move |mut stream: Stream<'i>| {
+        let mut result = Vec::with_capacity(parsers.len());
+        for (i, p) in parsers.iter().enumerate() {
+            let start = stream.clone();
+            let (s, r) = p.parse(stream).map_err(|err| {
+                let span = start.span_to(&start);
+                err.with_note(span, format!("sequence item {} starts here", i + 1))
+            })?;
+            stream = s;
+            result.push(r);
+        }
+        stream.ok(result)
+    }
+}
+
+/// Runs `f` with the user state borrowed. The borrow lasts for the whole call,
+/// so `f` and the parsers it runs must not call `Stream::state_mut`.
+pub fn with_state<'i, S: 'static, R, F: Clone + Fn(&S, Stream<'i>) -> PResult<'i, R>>(
+    f: F,
+) -> impl Parser<'i, Result = R> {
+    move |stream: Stream<'i>| {
+        let state = stream.state::<S>();
+        f(&state, stream.clone())
+    }
+}
+
+/// Testing helper for the purity contract combinators rely on: runs `parser`
+/// twice on fresh streams over `input` and panics unless both runs give the
+/// same result, or the same error, at the same position.
+pub fn assert_deterministic<'i, P: Parser<'i>>(parser: P, input: &'i str)
+where
+    P::Result: Debug + PartialEq,
+{
+    let run = || {
+        let result = parser.parse(Stream::new(input));
+        result.map(|(s, r)| (s.offset(), r))
+    };
+    let (first, second) = (run(), run());
+    assert_eq!(first, second, "parser is not deterministic on {input:?}");
+}
+
 pub const EOF: char = '\0';
 
 #[derive(Clone, Copy)]
@@ -1,5 +1,15 @@
-use super::{context::Context, Error, ErrorMessage, PResult};
-use std::{fmt::Debug, rc::Rc, str::Chars};
+use super::{
+    context::{clone_state, Catcher, Context, Mark, Memo, MemoKey, Recovered},
+    Error, ErrorMessage, PResult, Parser, Symbol,
+};
+use std::{
+    cell::{Ref, RefMut},
+    cmp::Reverse,
+    fmt::Debug,
+    mem::replace,
+    rc::Rc,
+    str::Chars,
+};
 
 #[derive(Clone)]
 pub struct Stream<'i> {
@@ -13,6 +23,9 @@ impl Debug for Stream<'_> {
     }
 }
 
+/// Compares only the remaining text, so streams of different inputs are equal
+/// when their rests match. Use `Stream::same_position` to also compare the
+/// parse they belong to.
 impl PartialEq for Stream<'_> {
     fn eq(&self, other: &Self) -> bool {
         self.chars.as_str() == other.chars.as_str()
@@ -21,8 +34,24 @@ impl PartialEq for Stream<'_> {
 
 impl Eq for Stream<'_> {}
 
+/// Where a stream is: a byte offset plus a 1-based line and column. Columns
+/// count Unicode scalar values and `\r\n` is a single line break.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
 impl<'i> Stream<'i> {
-    pub fn new(text: &'i str) -> Stream<'i> {
+    pub fn new<T: AsRef<str> + ?Sized>(text: &'i T) -> Stream<'i> {
+        let text = text.as_ref();
         let ctx = Context::new(text);
         Stream {
             chars: text.chars(),
@@ -30,11 +59,287 @@ impl<'i> Stream<'i> {
         }
     }
 
+    /// A stream carrying user state for `state`, `state_mut` and
+    /// `with_state`. The state is `Clone` so `Parser::would_match` can roll
+    /// it back.
+    pub fn new_with_state<T: AsRef<str> + ?Sized, S: Clone + 'static>(
+        text: &'i T,
+        state: S,
+    ) -> Stream<'i> {
+        let stream = Stream::new(text);
+        *stream.ctx.state.borrow_mut() = Box::new(state);
+        stream.ctx.clone_state.set(clone_state::<S>);
+        stream
+    }
+
+    /// Borrows the user state passed to `new_with_state`. Panics if `S` is not
+    /// its type or if the state is mutably borrowed.
+    pub fn state<S: 'static>(&self) -> Ref<'_, S> {
+        Ref::map(self.ctx.state.borrow(), |state| {
+            state.downcast_ref().expect("state has another type")
+        })
+    }
+
+    /// Mutably borrows the user state. Panics if `S` is not its type or if
+    /// the state is borrowed.
+    pub fn state_mut<S: 'static>(&self) -> RefMut<'_, S> {
+        RefMut::map(self.ctx.state.borrow_mut(), |state| {
+            state.downcast_mut().expect("state has another type")
+        })
+    }
+
+    /// Length of the whole input in bytes, including what has been parsed.
+    #[inline(always)]
+    pub fn text_len(&self) -> usize {
+        self.ctx.text_len
+    }
+
+    /// Length of the rest of the input in bytes. O(1).
     #[inline(always)]
     pub fn rest_len(&self) -> usize {
         self.chars.as_str().len()
     }
 
+    /// Number of chars (scalar values) left in the input. This walks the
+    /// rest, so it is O(n); compute it once instead of in a loop.
+    pub fn chars_remaining(&self) -> usize {
+        self.chars.clone().count()
+    }
+
+    #[inline(always)]
+    pub fn remaining(&self) -> &'i str {
+        self.chars.as_str()
+    }
+
+    #[inline(always)]
+    pub(super) fn advance_bytes(&self, len: usize) -> Stream<'i> {
+        Stream {
+            chars: self.chars.as_str()[len..].chars(),
+            ctx: self.ctx.clone(),
+        }
+    }
+
+    #[inline(always)]
+    pub fn offset(&self) -> usize {
+        self.ctx.offset_of(&self.chars)
+    }
+
+    /// Line and column come from an index of line starts built on the first
+    /// call, so this is a binary search after that.
+    pub fn position(&self) -> Position {
+        let offset = self.offset();
+        let (line, column) = self.ctx.line_col(offset);
+        Position {
+            offset,
+            line,
+            column,
+        }
+    }
+
+    pub fn span_to(&self, end: &Stream<'i>) -> Span {
+        Span {
+            start: self.offset(),
+            end: end.offset(),
+        }
+    }
+
+    /// The input between `self` and `end`, which must not be before `self`.
+    pub fn slice_to(&self, end: &Stream<'i>) -> &'i str {
+        &self.ctx.text[self.offset()..end.offset()]
+    }
+
+    pub(super) fn at(&self, offset: usize) -> Stream<'i> {
+        Stream {
+            chars: self.ctx.text[offset..].chars(),
+            ctx: self.ctx.clone(),
+        }
+    }
+
+    pub(super) fn window(&self, len: usize) -> Option<Stream<'i>> {
+        let window = self.chars.as_str().get(..len)?;
+        Some(Stream {
+            chars: window.chars(),
+            ctx: self.ctx.clone(),
+        })
+    }
+
+    pub(super) fn unwindow(&self) -> Stream<'i> {
+        self.ctx.catcher.borrow_mut().unwindow(self.ctx.text);
+        self.at(self.offset())
+    }
+
+    #[inline(always)]
+    pub fn same_context(&self, other: &Stream<'i>) -> bool {
+        Rc::ptr_eq(&self.ctx, &other.ctx)
+    }
+
+    #[inline(always)]
+    pub fn same_position(&self, other: &Stream<'i>) -> bool {
+        self.same_context(other) && self.offset() == other.offset()
+    }
+
+    #[cfg(debug_assertions)]
+    pub(super) fn debug_assert_advanced<R>(&self, result: &PResult<'i, R>) {
+        if let Ok((end, _)) = result {
+            debug_assert!(
+                end.offset() >= self.offset(),
+                "parser returned a stream before its input"
+            );
+            debug_assert!(
+                self.same_context(end),
+                "parser returned a stream of another context"
+            );
+        }
+    }
+
+    pub(super) fn line_col(&self) -> (usize, usize) {
+        self.ctx.line_col(self.offset())
+    }
+
+    pub(super) fn line_text(&self) -> &'i str {
+        let text = self.ctx.text;
+        let offset = self.offset();
+        let is_break = |c| c == '\n' || c == '\r';
+        let start = text[..offset].rfind(is_break).map_or(0, |i| i + 1);
+        let end = text[offset..]
+            .find(is_break)
+            .map_or(self.ctx.text_len, |i| offset + i);
+        &text[start..end]
+    }
+
+    pub fn is_at_line_start(&self) -> bool {
+        let before = &self.ctx.text[..self.offset()];
+        match before.chars().next_back() {
+            None | Some('\n') => true,
+            Some('\r') => !self.chars.as_str().starts_with('\n'),
+            _ => false,
+        }
+    }
+
+    #[cfg(feature = "trace")]
+    pub(super) fn is_furthest_error(&self) -> bool {
+        self.offset() >= self.ctx.offset_of(self.ctx.catcher.borrow().chars())
+    }
+
+    pub fn tokens(&self) -> Vec<(Span, &'static str)> {
+        let mut tokens = self.ctx.tokens.borrow().clone();
+        tokens.sort_by_key(|(span, _)| (span.start, Reverse(span.end)));
+        tokens
+    }
+
+    pub fn intern(&self, name: &'i str) -> Symbol {
+        self.ctx.symbols.borrow_mut().intern(name)
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> &'i str {
+        self.ctx.symbols.borrow().resolve(symbol)
+    }
+
+    pub fn recovered_errors(&self) -> Vec<Error<'i>> {
+        let recovered = self.ctx.recovered.borrow();
+        recovered.iter().map(|r| self.recovered_error(r)).collect()
+    }
+
+    fn recovered_error(&self, recovered: &Recovered<'i>) -> Error<'i> {
+        Error {
+            stream: self.at(self.ctx.offset_of(&recovered.chars)),
+            messages: recovered.messages.clone(),
+            notes: recovered.notes.clone(),
+            span: recovered.span,
+            code: recovered.code,
+            committed: recovered.committed,
+        }
+    }
+
+    pub(super) fn recover(&self, error: Error<'i>) {
+        self.ctx.recovered.borrow_mut().push(error.into());
+    }
+
+    pub(super) fn memo_get<R: Clone + 'static>(
+        &self,
+        name: &'static str,
+    ) -> Option<PResult<'i, R>> {
+        let memo = self.ctx.memo.borrow();
+        let entry = memo.get(&self.memo_key(name))?;
+        self.ctx.replay(&entry.trail);
+        if entry.cut {
+            self.ctx.cut.set(true);
+        }
+        Some(match &entry.result {
+            Ok((end, r)) => {
+                let r = r
+                    .downcast_ref::<R>()
+                    .expect("memoized rule name reused with another type");
+                self.advance_bytes(end - self.offset()).ok(r.clone())
+            }
+            Err(recovered) => Err(self.recovered_error(recovered)),
+        })
+    }
+
+    pub(super) fn memo_put<R: Clone + 'static>(
+        &self,
+        name: &'static str,
+        mark: &Mark<'i>,
+        cut: bool,
+        result: &PResult<'i, R>,
+    ) {
+        let trail = self.ctx.since(mark);
+        let result = match result {
+            Ok((s, r)) => Ok((s.offset(), Rc::new(r.clone()) as Rc<dyn std::any::Any>)),
+            Err(err) => Err(err.clone().into()),
+        };
+        let memo = Memo { result, trail, cut };
+        let key = self.memo_key(name);
+        self.ctx.memo.borrow_mut().insert(key, memo);
+        self.ctx.memo_log.borrow_mut().push(key);
+    }
+
+    fn memo_key(&self, name: &'static str) -> MemoKey {
+        let offset = self.offset();
+        let end = offset + self.rest_len();
+        (name, offset, end, self.ctx.skip_ws.get())
+    }
+
+    /// Runs `p` as one alternative of a choice. A `cut` inside it commits
+    /// only this alternative: on failure the error comes back with
+    /// `committed` set, and either way the outer cut state is restored.
+    pub(super) fn attempt<P: Parser<'i>>(&self, p: &P) -> PResult<'i, P::Result> {
+        let outer = self.ctx.cut.replace(false);
+        let result = p.parse(self.clone());
+        let cut = self.ctx.cut.replace(outer);
+        result.map_err(|mut err| {
+            err.committed |= cut;
+            err
+        })
+    }
+
+    /// `attempt` with a catcher of its own, so the error `p` fails with tells
+    /// how far `p` itself got rather than the furthest error of the parse.
+    /// What `p` caught is merged back into the outer catcher afterwards.
+    pub(super) fn attempt_isolated<P: Parser<'i>>(&self, p: &P) -> PResult<'i, P::Result> {
+        let mut fresh = Catcher::new(self.chars.clone());
+        let is_started = self.ctx.catcher.borrow().is_started;
+        fresh.set_started(is_started);
+        let outer = replace(&mut *self.ctx.catcher.borrow_mut(), fresh);
+        let result = self.attempt(p);
+        let inner = replace(&mut *self.ctx.catcher.borrow_mut(), outer);
+        let caught = inner.peek_error();
+        if !caught.messages.is_empty() {
+            self.catch(self.recovered_error(&caught));
+        }
+        result
+    }
+
+    #[inline(always)]
+    pub(super) fn mark(&self) -> Mark<'i> {
+        self.ctx.mark()
+    }
+
+    #[inline(always)]
+    pub(super) fn reset(&self, mark: &Mark<'i>) {
+        self.ctx.reset(mark)
+    }
+
     pub fn next(&self) -> (Stream<'i>, char) {
         let mut chars = self.chars.clone();
         let ch = chars.next().unwrap_or('\0');
@@ -59,19 +364,42 @@ impl<'i> Stream<'i> {
         Err(err)
     }
 
+    /// Fails with `message` at `span.start`, with `span` kept as the error's
+    /// span so reports can underline the whole of it. The error becomes the
+    /// caught one even when the catcher is further: it rejects input that
+    /// already parsed, so errors from looking past that input are moot.
+    pub fn err_at<R>(&self, span: Span, message: ErrorMessage) -> PResult<'i, R> {
+        let mut err = Error::new(self.at(span.start), message);
+        err.span = Some(span);
+        self.recatch(&err);
+        Err(err)
+    }
+
     pub fn catch(&self, error: Error<'i>) -> Error<'i> {
         let mut catcher = self.ctx.catcher.borrow_mut();
         if !catcher.is_started {
             return error;
         }
-        let (chars, messages) = catcher.pop_error(self.ctx.text.chars());
-        let mut stream = self.clone();
-        stream.chars = chars;
-        let err = error.or(Error { stream, messages });
+        let caught = catcher.pop_error(self.ctx.text.chars());
+        let err = error.or(self.recovered_error(&caught));
         catcher.set_error(err.clone());
         err
     }
 
+    /// Makes `error` the caught error, so a transformed error is what later
+    /// `catch` calls merge with instead of the one it was made from.
+    pub(super) fn recatch(&self, error: &Error<'i>) {
+        let mut catcher = self.ctx.catcher.borrow_mut();
+        if catcher.is_started {
+            catcher.set_error(error.clone());
+        }
+    }
+
+    pub(super) fn furthest_error(&self) -> Error<'i> {
+        let caught = self.ctx.catcher.borrow().peek_error();
+        self.recovered_error(&caught)
+    }
+
     /*pub fn clear_error(&self) {
         let mut catcher = self.ctx.catcher.borrow_mut();
         if catcher.rest_len() > self.rest_len() {